@@ -12,197 +12,5216 @@
 
 use sha2::{Digest, Sha256};
 use chrono::Utc;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Weak};
+
+/// Number of preceding blocks used to compute the median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Blocks a registered name stays valid for before it must be renewed
+const NAME_REGISTRATION_PERIOD: u64 = 100;
+
+/// Longest name the registry accepts
+const MAX_NAME_LENGTH: usize = 32;
+
+/// How far (in seconds) a block timestamp may run ahead of the local clock
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+/// Leading hex zeroes a proof hash needs to be accepted
+const DIFFICULTY_PREFIX: &str = "0000";
+
+/// Coins minted to the miner of each block, on top of the fees it collects
+const MINING_REWARD: f64 = 1.0;
+
+/// Lowest fee per serialized byte a mined transaction may pay
+const MIN_FEE_RATE: f64 = 0.000_001;
+
+/// Smallest amount an output of a mined transaction may carry
+const DUST_THRESHOLD: f64 = 0.001;
+
+/// Zero hex digits a mined transaction's hash needs to stand in for the minimum fee
+const MIN_STAMP_DIFFICULTY: usize = 3;
+
+/// Most serialized bytes of transactions a block may include, the coinbase
+/// among them
+const MAX_BLOCK_VSIZE: usize = 10_000;
+
+/// Width, in coins per byte, of each bucket in the pending fee histogram
+const FEE_HISTOGRAM_BUCKET: f64 = 0.000_001;
+
+/// Recent blocks whose lowest included fee rates inform fee estimates
+const FEE_ESTIMATE_BLOCKS: usize = 10;
+
+/// Most blocks a chain offered to `Blockchain::replace_chain` may add beyond
+/// our tip; longer catch-ups must arrive in several offers
+const MAX_NEW_BLOCKS_PER_OFFER: usize = 500;
+
+/// Block hashes each of the validation caches kept by `Blockchain::check_chain`
+/// remembers before forgetting the oldest
+const BLOCK_CACHE_CAPACITY: usize = 10_000;
+
+/// Blocks between the account state snapshots kept by `Blockchain::account_states`
+const STATE_SNAPSHOT_INTERVAL: u64 = 100;
+
+/// Decimal places of one coin; the smallest unit is one hundred-millionth
+const COIN_DECIMALS: u32 = 8;
+
+/// Blocks buried this deep below the tip are final and can never be reorganised away
+const FINALITY_DEPTH: u64 = 6;
+
+/// Scheme of payment request URIs, as in `chain:Alice?amount=0.5`
+const PAYMENT_URI_SCHEME: &str = "chain";
+
+/// Top bits of a block version that mark it as carrying version-bits signals
+const VERSION_BITS_TOP: u32 = 0x2000_0000;
+
+/// Mask selecting the top three bits of a block version
+const VERSION_BITS_TOP_MASK: u32 = 0xe000_0000;
+
+/// Blocks per signalling window; deployment states only change at window boundaries
+const SIGNALLING_WINDOW: u64 = 10;
+
+/// Signalling blocks needed within one window to lock a deployment in
+const SIGNALLING_THRESHOLD: usize = 8;
+
+/// A soft fork that miners signal readiness for through a version bit
+#[derive(Debug, Clone, Copy)]
+struct Deployment {
+    name: &'static str,
+    /// Version bit miners set to signal readiness
+    bit: u8,
+    /// First height at which signalling counts
+    start_height: u64,
+    /// Height after which a deployment that has not locked in fails
+    timeout_height: u64,
+}
+
+/// Once active, every block's coinbase nonce must equal the block's height
+const COINBASE_HEIGHT: Deployment = Deployment {
+    name: "coinbaseheight",
+    bit: 27,
+    start_height: 0,
+    timeout_height: 1_000,
+};
+
+/// Soft forks known to this node
+const DEPLOYMENTS: [Deployment; 2] = [
+    Deployment {
+        name: "testdummy",
+        bit: 28,
+        start_height: 0,
+        timeout_height: 1_000,
+    },
+    COINBASE_HEIGHT,
+];
+
+/// Activation state of a deployment for blocks in a given window
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DeploymentState {
+    Defined,
+    Started,
+    LockedIn,
+    Active,
+    Failed,
+}
+
+/// Follows one deployment's state as the blocks of a chain are read in order
+#[derive(Debug, Clone, Copy)]
+struct DeploymentTracker {
+    deployment: Deployment,
+    /// State for the next block
+    state: DeploymentState,
+    /// Blocks read so far
+    height: u64,
+    /// Signalling blocks read so far in the current window
+    signals: usize,
+}
+
+impl DeploymentTracker {
+    fn new(deployment: Deployment) -> Self {
+        DeploymentTracker { deployment, state: DeploymentState::Defined, height: 0, signals: 0 }
+    }
+
+    /// Reads the next block; at the end of a window the state advances based
+    /// on how many blocks in it set the deployment's version bit
+    fn push(&mut self, block: &Block) {
+        self.signals += usize::from(block.signals(&self.deployment));
+        self.height += 1;
+        if !self.height.is_multiple_of(SIGNALLING_WINDOW) {
+            return;
+        }
+        let window_start = self.height;
+        self.state = match self.state {
+            DeploymentState::Defined if window_start >= self.deployment.timeout_height => DeploymentState::Failed,
+            DeploymentState::Defined if window_start >= self.deployment.start_height => DeploymentState::Started,
+            DeploymentState::Started if self.signals >= SIGNALLING_THRESHOLD => DeploymentState::LockedIn,
+            DeploymentState::Started if window_start >= self.deployment.timeout_height => DeploymentState::Failed,
+            DeploymentState::LockedIn => DeploymentState::Active,
+            other => other,
+        };
+        self.signals = 0;
+    }
+}
+
+/// Output formats supported by `Blockchain::graph`
+#[derive(Debug, Clone, Copy)]
+enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// A source of the current Unix time, so the chain can run against a
+/// simulated or embedded clock instead of the system one
+trait Clock {
+    /// Returns the current Unix timestamp in seconds
+    fn now(&self) -> i64;
+}
+
+/// Reads the time from the operating system
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+}
+
+thread_local! {
+    /// The addresses alive on this thread, so equal addresses share one allocation
+    static ADDRESSES: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+/// Weak handles to interned addresses, bucketed by hash
+///
+/// Holding only weak handles lets an address be freed once its last clone is
+/// dropped. Dead handles are swept whenever the table doubles since the last
+/// sweep, so it stays proportional to the live addresses.
+#[derive(Default)]
+struct Interner {
+    hasher: RandomState,
+    buckets: HashMap<u64, Vec<Weak<str>>>,
+    entries: usize,
+    next_sweep: usize,
+}
+
+impl Interner {
+    fn intern(&mut self, address: &str) -> Arc<str> {
+        let bucket = self.buckets.entry(self.hasher.hash_one(address)).or_default();
+        if let Some(interned) = bucket.iter().filter_map(Weak::upgrade).find(|interned| &**interned == address) {
+            return interned;
+        }
+        let interned: Arc<str> = Arc::from(address);
+        bucket.push(Arc::downgrade(&interned));
+        self.entries += 1;
+        if self.entries > self.next_sweep {
+            self.sweep();
+        }
+        interned
+    }
+
+    fn sweep(&mut self) {
+        self.buckets.retain(|_, bucket| {
+            bucket.retain(|handle| handle.strong_count() > 0);
+            !bucket.is_empty()
+        });
+        self.entries = self.buckets.values().map(Vec::len).sum();
+        self.next_sweep = (self.entries * 2).max(64);
+    }
+}
+
+/// An interned account address
+///
+/// Clones share one allocation. Equal addresses created on the same thread
+/// share a pointer, so comparing them usually skips the string compare.
+#[derive(Clone, PartialOrd, Ord)]
+struct Address(Arc<str>);
+
+impl Address {
+    /// Returns the interned copy of `address`, creating it on first use
+    fn new(address: &str) -> Self {
+        ADDRESSES.with(|addresses| Address(addresses.borrow_mut().intern(address)))
+    }
+}
+
+impl From<&str> for Address {
+    fn from(address: &str) -> Self {
+        Address::new(address)
+    }
+}
+
+impl Deref for Address {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Address {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Address {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Address {}
+
+// Hashes like a `str`, as `Borrow<str>` requires
+impl Hash for Address {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl PartialEq<str> for Address {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Address {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+// Formats like a `String`, so transaction hashes are unchanged by interning
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An exact number of smallest units, for reading and printing amounts
+/// without floating-point surprises
+///
+/// Amounts are written in coins with `.` as the decimal point and optional
+/// `,` thousands separators, or as a whole number of smallest units followed
+/// by ` units`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Amount(i64);
+
+/// Reasons an amount cannot be read
+#[derive(Debug, Clone, PartialEq)]
+enum AmountError {
+    /// There are no digits
+    Empty,
+    /// A character other than a digit, separator or sign was found
+    InvalidCharacter(char),
+    /// A thousands separator is not followed by exactly three digits, or the
+    /// digits before the first one start with a zero, which also rejects a
+    /// comma used as the decimal point
+    MisplacedSeparator,
+    /// More decimal places than the smallest unit can hold
+    TooPrecise,
+    /// The amount does not fit in 64 bits of smallest units
+    Overflow,
+}
+
+impl Amount {
+    /// Smallest units in one coin
+    const UNITS_PER_COIN: i64 = 10_i64.pow(COIN_DECIMALS);
+
+    /// Converts a coin value, or `None` if it is not a whole number of
+    /// smallest units or is out of range
+    fn from_coins(coins: f64) -> Option<Self> {
+        let units = (coins * Self::UNITS_PER_COIN as f64).round();
+        if !units.is_finite() || units.abs() >= i64::MAX as f64 {
+            return None;
+        }
+        let exact = (units / Self::UNITS_PER_COIN as f64 - coins).abs() <= f64::EPSILON * coins.abs().max(1.0);
+        exact.then_some(Amount(units as i64))
+    }
+
+    /// Converts a coin value, rounding to the nearest smallest unit
+    fn nearest(coins: f64) -> Self {
+        Amount((coins * Self::UNITS_PER_COIN as f64).round() as i64)
+    }
+
+    /// Returns the amount in coins
+    fn to_coins(self) -> f64 {
+        self.0 as f64 / Self::UNITS_PER_COIN as f64
+    }
+
+    /// Reads an amount in coins, such as `1,234.5`, or in smallest units,
+    /// such as `150 units`
+    fn parse(text: &str) -> Result<Self, AmountError> {
+        let text = text.trim();
+        let (text, in_units) = match text.strip_suffix("units") {
+            Some(rest) => (rest.trim_end(), true),
+            None => (text, false),
+        };
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (whole, fraction) = match text.split_once('.') {
+            Some(_) if in_units => return Err(AmountError::TooPrecise),
+            Some((whole, fraction)) => (whole, fraction),
+            None => (text, ""),
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(AmountError::Empty);
+        }
+        let groups: Vec<&str> = whole.split(',').collect();
+        if groups.len() > 1
+            && (groups[0].is_empty()
+                || groups[0].len() > 3
+                || groups[0].starts_with('0')
+                || groups[1..].iter().any(|group| group.len() != 3))
+        {
+            return Err(AmountError::MisplacedSeparator);
+        }
+        if let Some(c) = groups.concat().chars().chain(fraction.chars()).find(|c| !c.is_ascii_digit()) {
+            return Err(if c == ',' { AmountError::MisplacedSeparator } else { AmountError::InvalidCharacter(c) });
+        }
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > COIN_DECIMALS as usize {
+            return Err(AmountError::TooPrecise);
+        }
+        // Whole coins followed by the fraction padded to a full set of decimals
+        let mut digits = groups.concat();
+        if !in_units {
+            digits.push_str(&format!("{:0<width$}", fraction, width = COIN_DECIMALS as usize));
+        }
+        let mut units: i64 = 0;
+        for digit in digits.bytes() {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(i64::from(digit - b'0')))
+                .ok_or(AmountError::Overflow)?;
+        }
+        Ok(Amount(if negative { -units } else { units }))
+    }
+}
+
+impl fmt::Display for Amount {
+    /// Prints the amount in coins with thousands separators and without
+    /// trailing zeros, such as `1,234.5`, or without separators, such as
+    /// `1234.5`, in the alternate form (`{:#}`)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = self.0.unsigned_abs();
+        let per_coin = Self::UNITS_PER_COIN as u64;
+        let whole = (units / per_coin).to_string();
+        let mut grouped = String::new();
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && !f.alternate() && (whole.len() - i).is_multiple_of(3) {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        let fraction = format!("{:0width$}", units % per_coin, width = COIN_DECIMALS as usize);
+        let fraction = fraction.trim_end_matches('0');
+        let sign = if self.0 < 0 { "-" } else { "" };
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, grouped)
+        } else {
+            write!(f, "{}{}.{}", sign, grouped, fraction)
+        }
+    }
+}
+
+/// A payment to one recipient within a transaction
+#[derive(Debug, Clone, PartialEq)]
+struct Output {
+    recipient: Address,
+    amount: f64,
+}
+
+impl Output {
+    /// Creates a new output
+    fn new(recipient: Address, amount: f64) -> Self {
+        Output { recipient, amount }
+    }
+}
 
 /// Represents a transaction in the blockchain
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 struct Transaction {
-    sender: String,
-    recipient: String,
-    amount: f64,
+    sender: Address,
+    /// Recipients paid by this transaction, each with its own amount
+    outputs: Vec<Output>,
+    /// Unix time before which the transaction may not be mined (0 means no lock)
+    lock_time: i64,
+    /// Position in the sender's sequence of transactions; two pending
+    /// transactions with the same sender and nonce conflict
+    nonce: u64,
+    /// Paid to the miner that includes the transaction
+    fee: f64,
+    /// Unique-asset action performed alongside any payments
+    asset: Option<AssetOperation>,
+    /// Name registry action performed alongside any payments
+    name: Option<NameOperation>,
+    /// Work nonce that lets a fee-less transaction meet the relay policy's
+    /// stamp difficulty (0 when unused)
+    stamp: u64,
+}
+
+/// Actions on unique (non-fungible) assets that a transaction can carry
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+enum AssetOperation {
+    /// Creates an asset owned by the sender; its id is the transaction's hash
+    Mint { metadata_hash: String },
+    /// Hands an asset the sender owns to `recipient`
+    Transfer { asset_id: String, recipient: Address },
+    /// Destroys an asset the sender owns
+    Burn { asset_id: String },
+}
+
+impl AssetOperation {
+    /// Returns the existing asset the operation acts on, if any
+    fn asset_id(&self) -> Option<&String> {
+        match self {
+            AssetOperation::Transfer { asset_id, .. } | AssetOperation::Burn { asset_id } => Some(asset_id),
+            AssetOperation::Mint { .. } => None,
+        }
+    }
+}
+
+/// Current owner of every unique asset, rebuilt by replaying asset operations
+#[derive(Debug, Clone, Default)]
+struct AssetRegistry {
+    owners: HashMap<String, Address>,
+}
+
+impl AssetRegistry {
+    /// Checks that the transaction's asset operation is allowed, without applying it
+    fn check(&self, transaction: &Transaction) -> Result<(), MempoolError> {
+        let Some(asset_id) = transaction.asset.as_ref().and_then(AssetOperation::asset_id) else {
+            return Ok(());
+        };
+        match self.owners.get(asset_id) {
+            None => Err(MempoolError::UnknownAsset),
+            Some(owner) if *owner != transaction.sender => Err(MempoolError::NotAssetOwner),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Applies the transaction's asset operation, if any
+    fn apply(&mut self, transaction: &Transaction) -> Result<(), MempoolError> {
+        self.check(transaction)?;
+        match &transaction.asset {
+            Some(AssetOperation::Mint { .. }) => {
+                self.owners.insert(transaction.hash(), transaction.sender.clone());
+            }
+            Some(AssetOperation::Transfer { asset_id, recipient }) => {
+                self.owners.insert(asset_id.clone(), recipient.clone());
+            }
+            Some(AssetOperation::Burn { asset_id }) => {
+                self.owners.remove(asset_id);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Actions on the first-come-first-served name registry
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+enum NameOperation {
+    /// Claims an unregistered or expired name, pointing it at `target`
+    Register { name: String, target: Address },
+    /// Extends the sender's registration by another period
+    Renew { name: String },
+    /// Hands the sender's registration to `recipient`
+    Transfer { name: String, recipient: Address },
+}
+
+impl NameOperation {
+    /// Returns the name the operation acts on
+    fn name(&self) -> &String {
+        match self {
+            NameOperation::Register { name, .. }
+            | NameOperation::Renew { name }
+            | NameOperation::Transfer { name, .. } => name,
+        }
+    }
+}
+
+/// Ownership and target of a registered name
+#[derive(Debug, Clone, PartialEq)]
+struct NameRecord {
+    owner: Address,
+    target: Address,
+    /// Last height at which the registration is valid
+    expires_at: u64,
+}
+
+/// Every registered name, rebuilt by replaying name operations
+#[derive(Debug, Clone, Default)]
+struct NameRegistry {
+    records: HashMap<String, NameRecord>,
+}
+
+impl NameRegistry {
+    /// Returns the record for a name that has not expired by `height`
+    fn active(&self, name: &str, height: u64) -> Option<&NameRecord> {
+        self.records.get(name).filter(|record| record.expires_at >= height)
+    }
+
+    /// Checks that the transaction's name operation is allowed in a block at
+    /// `height`, without applying it
+    fn check(&self, transaction: &Transaction, height: u64) -> Result<(), MempoolError> {
+        let Some(operation) = &transaction.name else {
+            return Ok(());
+        };
+        match (operation, self.active(operation.name(), height)) {
+            (NameOperation::Register { .. }, Some(_)) => Err(MempoolError::NameTaken),
+            (NameOperation::Register { .. }, None) => Ok(()),
+            (_, None) => Err(MempoolError::UnknownName),
+            (_, Some(record)) if record.owner != transaction.sender => Err(MempoolError::NotNameOwner),
+            (_, Some(_)) => Ok(()),
+        }
+    }
+
+    /// Applies the transaction's name operation, if any, in a block at `height`
+    fn apply(&mut self, transaction: &Transaction, height: u64) -> Result<(), MempoolError> {
+        self.check(transaction, height)?;
+        match &transaction.name {
+            Some(NameOperation::Register { name, target }) => {
+                let record = NameRecord {
+                    owner: transaction.sender.clone(),
+                    target: target.clone(),
+                    expires_at: height + NAME_REGISTRATION_PERIOD,
+                };
+                self.records.insert(name.clone(), record);
+            }
+            Some(NameOperation::Renew { name }) => {
+                if let Some(record) = self.records.get_mut(name) {
+                    record.expires_at += NAME_REGISTRATION_PERIOD;
+                }
+            }
+            Some(NameOperation::Transfer { name, recipient }) => {
+                if let Some(record) = self.records.get_mut(name) {
+                    record.owner = recipient.clone();
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// Checks whether the transaction may be included in a block whose
+    /// predecessors have the given median time past
+    fn is_final(&self, median_time_past: i64) -> bool {
+        self.lock_time == 0 || self.lock_time < median_time_past
+    }
+
+    /// Checks that the transaction is well formed on its own
+    fn check(&self) -> Result<(), MempoolError> {
+        if self.outputs.is_empty() && self.asset.is_none() && self.name.is_none() {
+            return Err(MempoolError::NoOutputs);
+        }
+        if let Some(operation) = &self.name {
+            let name = operation.name();
+            let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+            if name.is_empty() || name.len() > MAX_NAME_LENGTH || !name.chars().all(allowed) {
+                return Err(MempoolError::InvalidName);
+            }
+            if let NameOperation::Transfer { recipient, .. } = operation {
+                if recipient.is_empty() {
+                    return Err(MempoolError::MissingAddress);
+                }
+            }
+        }
+        if let Some(AssetOperation::Transfer { recipient, .. }) = &self.asset {
+            if recipient.is_empty() {
+                return Err(MempoolError::MissingAddress);
+            }
+        }
+        if self.sender.is_empty() || self.outputs.iter().any(|output| output.recipient.is_empty()) {
+            return Err(MempoolError::MissingAddress);
+        }
+        if self.outputs.iter().any(|output| !output.amount.is_finite() || output.amount <= 0.0) {
+            return Err(MempoolError::InvalidAmount);
+        }
+        if !self.fee.is_finite() || self.fee < 0.0 {
+            return Err(MempoolError::InvalidFee);
+        }
+        Ok(())
+    }
+
+    /// Calculates the hash of the transaction
+    fn hash(&self) -> String {
+        format!("{:x}", Sha256::digest(format!("{:?}", self).as_bytes()))
+    }
+
+    /// Encodes the transaction in the canonical binary format: fixed-width
+    /// little-endian numbers, length-prefixed strings and lists, and a tag byte
+    /// before each optional operation
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        encode_str(&mut buffer, &self.sender);
+        buffer.extend((self.outputs.len() as u32).to_le_bytes());
+        for output in &self.outputs {
+            encode_str(&mut buffer, &output.recipient);
+            buffer.extend(output.amount.to_le_bytes());
+        }
+        buffer.extend(self.lock_time.to_le_bytes());
+        buffer.extend(self.nonce.to_le_bytes());
+        buffer.extend(self.fee.to_le_bytes());
+        match &self.asset {
+            None => buffer.push(0),
+            Some(AssetOperation::Mint { metadata_hash }) => {
+                buffer.push(1);
+                encode_str(&mut buffer, metadata_hash);
+            }
+            Some(AssetOperation::Transfer { asset_id, recipient }) => {
+                buffer.push(2);
+                encode_str(&mut buffer, asset_id);
+                encode_str(&mut buffer, recipient);
+            }
+            Some(AssetOperation::Burn { asset_id }) => {
+                buffer.push(3);
+                encode_str(&mut buffer, asset_id);
+            }
+        }
+        match &self.name {
+            None => buffer.push(0),
+            Some(NameOperation::Register { name, target }) => {
+                buffer.push(1);
+                encode_str(&mut buffer, name);
+                encode_str(&mut buffer, target);
+            }
+            Some(NameOperation::Renew { name }) => {
+                buffer.push(2);
+                encode_str(&mut buffer, name);
+            }
+            Some(NameOperation::Transfer { name, recipient }) => {
+                buffer.push(3);
+                encode_str(&mut buffer, name);
+                encode_str(&mut buffer, recipient);
+            }
+        }
+        buffer.extend(self.stamp.to_le_bytes());
+        buffer
+    }
+
+    /// Returns the serialized size in bytes; transactions carry no separate
+    /// witness data, so this is also their weight divided by four
+    fn vsize(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// Searches for a stamp whose hash starts with `difficulty` zero hex digits
+    fn mint_stamp(&mut self, difficulty: usize) {
+        let prefix = "0".repeat(difficulty);
+        self.stamp = 0;
+        while !self.hash().starts_with(&prefix) {
+            self.stamp += 1;
+        }
+    }
+
+    /// Returns what the transaction debits from its sender: every output
+    /// plus the fee
+    fn spend(&self) -> f64 {
+        self.outputs.iter().map(|output| output.amount).sum::<f64>() + self.fee
+    }
+
+    /// Returns the fee paid per serialized byte
+    fn fee_rate(&self) -> f64 {
+        self.fee / self.vsize() as f64
+    }
+}
+
+/// Appends a string to a serialized buffer, prefixed by its byte length
+fn encode_str(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend((value.len() as u32).to_le_bytes());
+    buffer.extend(value.as_bytes());
+}
+
+/// Hashes two adjacent Merkle nodes into their parent
+fn merkle_parent(left: &str, right: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{}{}", left, right).as_bytes()))
+}
+
+/// Every level of a Merkle tree over transaction hashes, leaves first, kept so
+/// inclusion proofs need no rehashing
+///
+/// The last hash is duplicated on levels with an odd number of entries.
+#[derive(Debug, Clone)]
+struct MerkleTree {
+    levels: Vec<Vec<String>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree level by level
+    fn new(transactions: &[Transaction]) -> Self {
+        let mut levels = vec![transactions.iter().map(Transaction::hash).collect::<Vec<_>>()];
+        while levels[levels.len() - 1].len() > 1 {
+            let parents = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            levels.push(parents);
+        }
+        MerkleTree { levels }
+    }
+
+    /// Returns the root hash, or `"0"` for an empty tree
+    fn root(&self) -> String {
+        self.levels[self.levels.len() - 1].first().cloned().unwrap_or_else(|| String::from("0"))
+    }
+
+    /// Returns the sibling hashes from leaf `index` up to the root, each paired
+    /// with whether it sits on the right
+    fn proof(&self, index: usize) -> Option<Vec<(String, bool)>> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let mut position = index;
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            let hash = level.get(sibling).unwrap_or(&level[position]);
+            proof.push((hash.clone(), sibling > position));
+            position /= 2;
+        }
+        Some(proof)
+    }
+}
+
+/// Checks that `proof` links the transaction hash `leaf` to `root`
+fn verify_merkle_proof(leaf: &str, proof: &[(String, bool)], root: &str) -> bool {
+    let computed = proof.iter().fold(String::from(leaf), |hash, (sibling, on_right)| {
+        if *on_right {
+            merkle_parent(&hash, sibling)
+        } else {
+            merkle_parent(sibling, &hash)
+        }
+    });
+    computed == root
 }
 
 /// Represents a block in the blockchain
 #[derive(Debug, Clone)]
 struct Block {
     index: u64,
+    /// Header version, whose low bits carry soft-fork signals
+    version: u32,
     timestamp: i64,
     transactions: Vec<Transaction>,
     proof: u64,
     previous_hash: String,
+    /// Commitment to every account's balance and next nonce once this block's
+    /// transactions are applied, as computed by `state_root`
+    state_root: String,
 }
 
 impl Block {
     /// Creates a new block
-    fn new(index: u64, transactions: Vec<Transaction>, proof: u64, previous_hash: String) -> Self {
+    fn new(
+        index: u64,
+        version: u32,
+        timestamp: i64,
+        transactions: Vec<Transaction>,
+        proof: u64,
+        previous_hash: String,
+        state_root: String,
+    ) -> Self {
         Block {
             index,
-            timestamp: Utc::now().timestamp(),
+            version,
+            timestamp,
             transactions,
             proof,
             previous_hash,
+            state_root,
         }
     }
 
     /// Calculates the hash of the block
     fn calculate_hash(&self) -> String {
         let mut hasher = Sha256::new();
-        let data = format!("{}{}{:?}{}{}", self.index, self.timestamp, self.transactions, self.proof, self.previous_hash);
+        let data = format!(
+            "{}{}{}{:?}{}{}{}",
+            self.index, self.version, self.timestamp, self.transactions, self.proof, self.previous_hash, self.state_root
+        );
         hasher.update(data);
         format!("{:x}", hasher.finalize())
     }
+
+    /// Encodes the block header followed by its transactions in the canonical
+    /// binary format
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend(self.index.to_le_bytes());
+        buffer.extend(self.version.to_le_bytes());
+        buffer.extend(self.timestamp.to_le_bytes());
+        buffer.extend(self.proof.to_le_bytes());
+        encode_str(&mut buffer, &self.previous_hash);
+        encode_str(&mut buffer, &self.state_root);
+        buffer.extend((self.transactions.len() as u32).to_le_bytes());
+        for transaction in &self.transactions {
+            buffer.extend(transaction.serialize());
+        }
+        buffer
+    }
+
+    /// Returns the serialized size of the block in bytes
+    fn size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// Checks that the block stays within the size limit, opens with its only
+    /// coinbase and every transaction is well formed, without hashing anything
+    fn check(&self) -> Result<(), BlockRejection> {
+        let vsize: usize = self.transactions.iter().map(Transaction::vsize).sum();
+        if vsize > MAX_BLOCK_VSIZE {
+            return Err(BlockRejection::TooLarge);
+        }
+        let (coinbase, transactions) = self.transactions.split_first().ok_or(BlockRejection::BadCoinbase)?;
+        let paid = coinbase
+            .outputs
+            .iter()
+            .fold(Amount(0), |paid, output| Amount(paid.0.saturating_add(Amount::nearest(output.amount).0)));
+        if coinbase.sender != "0"
+            || coinbase.fee != 0.0
+            || coinbase.asset.is_some()
+            || coinbase.name.is_some()
+            || paid > block_reward(transactions)
+            || transactions.iter().any(|transaction| transaction.sender == "0")
+        {
+            return Err(BlockRejection::BadCoinbase);
+        }
+        self.transactions
+            .iter()
+            .try_for_each(Transaction::check)
+            .map_err(BlockRejection::InvalidTransaction)
+    }
+
+    /// Checks whether the block signals readiness for the deployment
+    fn signals(&self, deployment: &Deployment) -> bool {
+        self.version & VERSION_BITS_TOP_MASK == VERSION_BITS_TOP && self.version & (1 << deployment.bit) != 0
+    }
 }
 
-/// Represents the blockchain
-struct Blockchain {
-    chain: Vec<Block>,
-    current_transactions: Vec<Transaction>,
+/// The most a block's coinbase may pay: the mining reward plus the fees of
+/// the block's other transactions
+fn block_reward(transactions: &[Transaction]) -> Amount {
+    transactions.iter().fold(Amount::nearest(MINING_REWARD), |reward, transaction| {
+        Amount(reward.0.saturating_add(Amount::nearest(transaction.fee).0))
+    })
 }
 
-impl Blockchain {
-    /// Creates a new blockchain with a genesis block
-    fn new() -> Self {
-        let mut chain = Vec::new();
-        chain.push(Block::new(0, Vec::new(), 100, String::from("0")));
-        Blockchain {
-            chain,
-            current_transactions: Vec::new(),
-        }
+/// Strings held by the encoded Mint, Transfer and Burn asset operations
+const ASSET_OPERATION_STRINGS: [usize; 3] = [1, 2, 1];
+
+/// Strings held by the encoded Register, Renew and Transfer name operations
+const NAME_OPERATION_STRINGS: [usize; 3] = [2, 1, 2];
+
+/// Cursor over the canonical binary encoding that hands out borrowed fields;
+/// every read returns `None` once the input runs short or is malformed
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, position: 0 }
     }
 
-    /// Adds a new transaction to the list of current transactions
-    fn new_transaction(&mut self, sender: String, recipient: String, amount: f64) -> usize {
-        self.current_transactions.push(Transaction { sender, recipient, amount });
-        self.last_block().index as usize + 1
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+        Some(bytes)
     }
 
-    /// Creates a new block and adds it to the chain
-    fn new_block(&mut self, proof: u64) -> Block {
-        let previous_hash = self.last_block().calculate_hash();
-        let block = Block::new(
-            self.chain.len() as u64,
-            std::mem::take(&mut self.current_transactions),
-            proof,
-            previous_hash,
-        );
-        self.chain.push(block.clone());
-        block
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
     }
-    
-    /// Returns a reference to the last block in the chain
-    fn last_block(&self) -> &Block {
-        self.chain.last().unwrap()
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
     }
 
-    /// Implements a simple proof-of-work algorithm
-    fn proof_of_work(&self, last_proof: u64) -> u64 {
-        let mut proof = 0;
-        while !self.valid_proof(last_proof, proof) {
-            proof += 1;
-        }
-        proof
+    fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
     }
 
-    /// Validates the proof: does hash(last_proof, proof) contain 4 leading zeroes?
-    fn valid_proof(&self, last_proof: u64, proof: u64) -> bool {
-        let guess = format!("{}{}", last_proof, proof);
-        let guess_hash = Sha256::digest(guess.as_bytes());
-        let result = format!("{:x}", guess_hash);
-        result.starts_with("0000")
+    fn u64(&mut self) -> Option<u64> {
+        self.array().map(u64::from_le_bytes)
     }
-}
 
-fn main() {
-    // Create a new blockchain
-    let mut blockchain = Blockchain::new();
-    
-    // Mine the first block
-    println!("Mining first block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("0"), String::from("Alice"), 1.0);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the second block
-    println!("Mining second block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Alice"), String::from("Bob"), 0.5);
-    blockchain.new_transaction(String::from("Alice"), String::from("Charlie"), 0.3);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-    // Mine the third block
-    println!("Mining third block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Bob"), String::from("David"), 0.2);
-    blockchain.new_transaction(String::from("Charlie"), String::from("Eve"), 0.1);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-    // Mine the fourth block
-    println!("Mining fourth block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("David"), String::from("Frank"), 0.3);
-    blockchain.new_transaction(String::from("Eve"), String::from("Grace"), 0.2);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the fifth block
-    println!("Mining fifth block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Frank"), String::from("Henry"), 0.4);
-    blockchain.new_transaction(String::from("Grace"), String::from("Ivy"), 0.1);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the sixth block
-    println!("Mining sixth block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Henry"), String::from("Jack"), 0.2);
-    blockchain.new_transaction(String::from("Ivy"), String::from("Kelly"), 0.3);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the seventh block
-    println!("Mining seventh block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Jack"), String::from("Liam"), 0.5);
-    blockchain.new_transaction(String::from("Kelly"), String::from("Mia"), 0.1);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the eighth block
-    println!("Mining eighth block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Liam"), String::from("Noah"), 0.3);
-    blockchain.new_transaction(String::from("Mia"), String::from("Olivia"), 0.2);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the ninth block
-    println!("Mining ninth block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Noah"), String::from("Peter"), 0.4);
-    blockchain.new_transaction(String::from("Olivia"), String::from("Quinn"), 0.1);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
-
-    // Mine the tenth block
-    println!("Mining tenth block...");
-    let last_proof = blockchain.last_block().proof;
-    let proof = blockchain.proof_of_work(last_proof);
-    blockchain.new_transaction(String::from("Peter"), String::from("Rachel"), 0.2);
-    blockchain.new_transaction(String::from("Quinn"), String::from("Sam"), 0.3);
-    let block = blockchain.new_block(proof);
-    println!("New block forged: {:?}", block);
+    fn i64(&mut self) -> Option<i64> {
+        self.array().map(i64::from_le_bytes)
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        self.array().map(f64::from_le_bytes)
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).ok()
+    }
+
+    /// Skips a tagged operation whose variant with tag `n` holds
+    /// `strings_per_tag[n - 1]` strings, returning the tag
+    fn operation(&mut self, strings_per_tag: [usize; 3]) -> Option<u8> {
+        let tag = self.u8()?;
+        let strings = match tag {
+            0 => 0,
+            1..=3 => strings_per_tag[tag as usize - 1],
+            _ => return None,
+        };
+        for _ in 0..strings {
+            self.str()?;
+        }
+        Some(tag)
+    }
+
+    /// Reads the bytes consumed since `start`
+    fn since(&self, start: usize) -> &'a [u8] {
+        &self.bytes[start..self.position]
+    }
+}
+
+/// A transaction read in place from its canonical encoding, so hot paths can
+/// inspect it without allocating
+#[derive(Debug, Clone, Copy)]
+struct TransactionRef<'a> {
+    sender: &'a str,
+    output_count: u32,
+    /// Encoded outputs, read on demand by `outputs`
+    output_bytes: &'a [u8],
+    lock_time: i64,
+    nonce: u64,
+    fee: f64,
+    /// Tagged asset operation, tag 0 meaning none
+    asset_bytes: &'a [u8],
+    /// Tagged name operation, tag 0 meaning none
+    name_bytes: &'a [u8],
+    stamp: u64,
+}
+
+impl<'a> TransactionRef<'a> {
+    /// Reads one transaction from the decoder, checking its structure
+    fn decode(decoder: &mut Decoder<'a>) -> Option<Self> {
+        let sender = decoder.str()?;
+        let output_count = decoder.u32()?;
+        let outputs_start = decoder.position;
+        for _ in 0..output_count {
+            decoder.str()?;
+            decoder.f64()?;
+        }
+        let output_bytes = decoder.since(outputs_start);
+        let lock_time = decoder.i64()?;
+        let nonce = decoder.u64()?;
+        let fee = decoder.f64()?;
+        let asset_start = decoder.position;
+        decoder.operation(ASSET_OPERATION_STRINGS)?;
+        let asset_bytes = decoder.since(asset_start);
+        let name_start = decoder.position;
+        decoder.operation(NAME_OPERATION_STRINGS)?;
+        let name_bytes = decoder.since(name_start);
+        let stamp = decoder.u64()?;
+        Some(TransactionRef {
+            sender,
+            output_count,
+            output_bytes,
+            lock_time,
+            nonce,
+            fee,
+            asset_bytes,
+            name_bytes,
+            stamp,
+        })
+    }
+
+    /// Views a single encoded transaction, rejecting trailing bytes
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(bytes);
+        let transaction = Self::decode(&mut decoder)?;
+        (decoder.position == bytes.len()).then_some(transaction)
+    }
+
+    /// Iterates over each output's recipient and amount
+    fn outputs(&self) -> impl Iterator<Item = (&'a str, f64)> {
+        let mut decoder = Decoder::new(self.output_bytes);
+        (0..self.output_count).map(move |_| {
+            let recipient = decoder.str().expect("outputs were checked when the view was created");
+            let amount = decoder.f64().expect("outputs were checked when the view was created");
+            (recipient, amount)
+        })
+    }
+
+    fn has_asset_operation(&self) -> bool {
+        self.asset_bytes[0] != 0
+    }
+
+    fn has_name_operation(&self) -> bool {
+        self.name_bytes[0] != 0
+    }
+
+    /// Copies the view into an owned transaction
+    fn to_transaction(self) -> Transaction {
+        let mut asset_decoder = Decoder::new(&self.asset_bytes[1..]);
+        let mut field = || asset_decoder.str().expect("operations were checked when the view was created");
+        let asset = match self.asset_bytes[0] {
+            1 => Some(AssetOperation::Mint { metadata_hash: String::from(field()) }),
+            2 => Some(AssetOperation::Transfer { asset_id: String::from(field()), recipient: Address::from(field()) }),
+            3 => Some(AssetOperation::Burn { asset_id: String::from(field()) }),
+            _ => None,
+        };
+        let mut name_decoder = Decoder::new(&self.name_bytes[1..]);
+        let mut field = || name_decoder.str().expect("operations were checked when the view was created");
+        let name = match self.name_bytes[0] {
+            1 => Some(NameOperation::Register { name: String::from(field()), target: Address::from(field()) }),
+            2 => Some(NameOperation::Renew { name: String::from(field()) }),
+            3 => Some(NameOperation::Transfer { name: String::from(field()), recipient: Address::from(field()) }),
+            _ => None,
+        };
+        Transaction {
+            sender: Address::from(self.sender),
+            outputs: self.outputs().map(|(recipient, amount)| Output::new(Address::from(recipient), amount)).collect(),
+            lock_time: self.lock_time,
+            nonce: self.nonce,
+            fee: self.fee,
+            asset,
+            name,
+            stamp: self.stamp,
+        }
+    }
+}
+
+/// A block read in place from its canonical encoding
+#[derive(Debug, Clone, Copy)]
+struct BlockRef<'a> {
+    index: u64,
+    version: u32,
+    timestamp: i64,
+    proof: u64,
+    previous_hash: &'a str,
+    state_root: &'a str,
+    transaction_count: u32,
+    /// Encoded transactions, read on demand by `transactions`
+    transaction_bytes: &'a [u8],
+}
+
+impl<'a> BlockRef<'a> {
+    /// Views an encoded block, checking the structure of every transaction
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(bytes);
+        let index = decoder.u64()?;
+        let version = decoder.u32()?;
+        let timestamp = decoder.i64()?;
+        let proof = decoder.u64()?;
+        let previous_hash = decoder.str()?;
+        let state_root = decoder.str()?;
+        let transaction_count = decoder.u32()?;
+        let transactions_start = decoder.position;
+        for _ in 0..transaction_count {
+            TransactionRef::decode(&mut decoder)?;
+        }
+        if decoder.position != bytes.len() {
+            return None;
+        }
+        Some(BlockRef {
+            index,
+            version,
+            timestamp,
+            proof,
+            previous_hash,
+            state_root,
+            transaction_count,
+            transaction_bytes: decoder.since(transactions_start),
+        })
+    }
+
+    /// Iterates over views of the block's transactions
+    fn transactions(&self) -> impl Iterator<Item = TransactionRef<'a>> {
+        let mut decoder = Decoder::new(self.transaction_bytes);
+        (0..self.transaction_count).map(move |_| {
+            TransactionRef::decode(&mut decoder).expect("transactions were checked when the view was created")
+        })
+    }
+
+    /// Copies the view into an owned block
+    fn to_block(self) -> Block {
+        Block::new(
+            self.index,
+            self.version,
+            self.timestamp,
+            self.transactions().map(TransactionRef::to_transaction).collect(),
+            self.proof,
+            String::from(self.previous_hash),
+            String::from(self.state_root),
+        )
+    }
+}
+
+/// Everything a miner needs to search for a proof without touching the chain
+#[derive(Debug, Clone)]
+struct BlockTemplate {
+    index: u64,
+    version: u32,
+    timestamp: i64,
+    previous_hash: String,
+    /// Proof of the previous block, which the nonce search starts from
+    last_proof: u64,
+    /// Hex prefix the proof hash must start with
+    target: String,
+    coinbase: Transaction,
+    transactions: Vec<Transaction>,
+    /// Merkle root over the coinbase followed by the selected transactions
+    merkle_root: String,
+    /// The tree behind `merkle_root`, kept for inclusion proofs
+    merkle_tree: MerkleTree,
+    /// Account state commitment once the coinbase and selected transactions apply
+    state_root: String,
+}
+
+impl BlockTemplate {
+    /// Assembles the block the template describes, the coinbase first
+    fn into_block(self, proof: u64) -> Block {
+        let mut transactions = vec![self.coinbase];
+        transactions.extend(self.transactions);
+        Block::new(
+            self.index,
+            self.version,
+            self.timestamp,
+            transactions,
+            proof,
+            self.previous_hash,
+            self.state_root,
+        )
+    }
+}
+
+/// Reasons `Blockchain::submit_block` can turn down a completed template
+#[derive(Debug, Clone, PartialEq)]
+enum SubmitBlockError {
+    /// The chain tip moved since the template was built
+    StaleTemplate,
+    /// The proof does not satisfy the template's target
+    InvalidProof,
+    /// The block breaks a rule `Blockchain::check_chain` enforces
+    Rejected(BlockRejection),
+}
+
+impl SubmitBlockError {
+    /// Returns a stable, machine-readable name for the reason
+    fn code(&self) -> &'static str {
+        match self {
+            SubmitBlockError::StaleTemplate => "stale-template",
+            SubmitBlockError::InvalidProof => "bad-proof",
+            SubmitBlockError::Rejected(reason) => reason.code(),
+        }
+    }
+}
+
+/// Reasons a block fails chain validation
+#[derive(Debug, Clone, PartialEq)]
+enum BlockRejection {
+    /// The chain is empty or its genesis block does not commit to an empty state
+    BadGenesis,
+    /// The block's index does not follow the previous block's
+    WrongIndex,
+    /// The block does not build on the previous block's hash
+    StaleParent,
+    /// The block's transactions exceed `MAX_BLOCK_VSIZE`
+    TooLarge,
+    /// A transaction is malformed on its own
+    InvalidTransaction(MempoolError),
+    /// The proof does not satisfy the target, now or on an earlier attempt
+    InvalidProof,
+    /// The timestamp is not after the median time past or is too far in the future
+    BadTimestamp,
+    /// A transaction's lock time has not passed
+    NotFinal,
+    /// A transaction's nonce is not the next one its sender has not used
+    BadNonce,
+    /// A transaction spends more than its sender's balance
+    InsufficientFunds,
+    /// A transaction breaks the consensus fee, dust or stamp rules
+    FeeRules(MempoolError),
+    /// An asset or name operation is not allowed by the state before it
+    InvalidOperation(MempoolError),
+    /// The state root does not match the replayed account state
+    BadStateRoot,
+    /// The block does not open with exactly one coinbase paying at most the
+    /// mining reward plus fees, or once `COINBASE_HEIGHT` is active, its
+    /// coinbase nonce is not the block's height
+    BadCoinbase,
+}
+
+impl BlockRejection {
+    /// Returns a stable, machine-readable name for the reason; rejected
+    /// transactions report their own code
+    fn code(&self) -> &'static str {
+        match self {
+            BlockRejection::BadGenesis => "bad-genesis",
+            BlockRejection::WrongIndex => "wrong-index",
+            BlockRejection::StaleParent => "stale-parent",
+            BlockRejection::TooLarge => "too-large",
+            BlockRejection::InvalidProof => "bad-proof",
+            BlockRejection::BadTimestamp => "bad-timestamp",
+            BlockRejection::NotFinal => "non-final",
+            BlockRejection::BadNonce => "bad-nonce",
+            BlockRejection::InsufficientFunds => "insufficient-funds",
+            BlockRejection::BadStateRoot => "bad-state-root",
+            BlockRejection::BadCoinbase => "bad-coinbase",
+            BlockRejection::InvalidTransaction(error)
+            | BlockRejection::FeeRules(error)
+            | BlockRejection::InvalidOperation(error) => error.code(),
+        }
+    }
+}
+
+/// The first block of a chain to fail validation, and why
+#[derive(Debug, Clone, PartialEq)]
+struct InvalidBlock {
+    height: u64,
+    reason: BlockRejection,
+}
+
+/// Rules for replacing a pending transaction with a conflicting one
+#[derive(Debug, Clone, Copy)]
+struct ReplacementPolicy {
+    /// Whether conflicting transactions may replace pending ones at all
+    enabled: bool,
+    /// How much the replacement's fee must exceed the original fee by
+    min_fee_increase: f64,
+}
+
+impl Default for ReplacementPolicy {
+    fn default() -> Self {
+        ReplacementPolicy {
+            enabled: true,
+            min_fee_increase: 0.001,
+        }
+    }
+}
+
+/// Anti-spam limits a node applies when admitting transactions
+///
+/// The coinbase, sent by the `"0"` address, is exempt. Blocks are held to the fixed
+/// `RelayPolicy::CONSENSUS` instead, which admission also checks, so a node
+/// can relay more strictly than consensus but never more loosely.
+#[derive(Debug, Clone, Copy)]
+struct RelayPolicy {
+    /// Lowest fee per serialized byte a transaction must pay
+    min_fee_rate: f64,
+    /// Smallest amount an output may carry
+    dust_threshold: f64,
+    /// Most transactions one sender may have pending at once
+    max_pending_transactions: usize,
+    /// Most coins one sender may have pending at once, fees included
+    max_pending_value: f64,
+    /// Zero hex digits a transaction's hash needs to skip the minimum fee, for
+    /// fee-less networks (`None` disables stamps)
+    stamp_difficulty: Option<usize>,
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy {
+            min_fee_rate: MIN_FEE_RATE,
+            dust_threshold: DUST_THRESHOLD,
+            max_pending_transactions: 25,
+            max_pending_value: 100.0,
+            stamp_difficulty: None,
+        }
+    }
+}
+
+impl RelayPolicy {
+    /// The fee, dust and stamp rules every mined transaction must meet, fixed
+    /// so a block's validity never depends on a node's local settings
+    const CONSENSUS: RelayPolicy = RelayPolicy {
+        min_fee_rate: MIN_FEE_RATE,
+        dust_threshold: DUST_THRESHOLD,
+        max_pending_transactions: usize::MAX,
+        max_pending_value: f64::INFINITY,
+        stamp_difficulty: Some(MIN_STAMP_DIFFICULTY),
+    };
+
+    /// Returns the lowest fee `transaction` may pay
+    fn min_fee(&self, transaction: &Transaction) -> f64 {
+        self.min_fee_rate * transaction.vsize() as f64
+    }
+
+    /// Checks the transaction's fee and output amounts against the policy
+    fn check(&self, transaction: &Transaction) -> Result<(), MempoolError> {
+        if transaction.sender == "0" {
+            return Ok(());
+        }
+        if transaction.outputs.iter().any(|output| output.amount < self.dust_threshold) {
+            return Err(MempoolError::DustOutput);
+        }
+        // Only hash the transaction when the fee alone falls short
+        let required = self.min_fee(transaction);
+        let stamped = || {
+            self.stamp_difficulty
+                .is_some_and(|difficulty| transaction.hash().starts_with(&"0".repeat(difficulty)))
+        };
+        if transaction.fee < required && !stamped() {
+            return Err(MempoolError::FeeTooLow { required });
+        }
+        Ok(())
+    }
+}
+
+/// Pending transactions grouped by fee rate, updated as the pending list
+/// changes so fee estimates need not sort it
+#[derive(Debug, Clone, Default)]
+struct FeeHistogram {
+    /// Transaction count and total vsize per `FEE_HISTOGRAM_BUCKET`-wide bucket,
+    /// keyed by bucket number
+    buckets: BTreeMap<u64, (usize, usize)>,
+}
+
+impl FeeHistogram {
+    fn bucket(transaction: &Transaction) -> u64 {
+        (transaction.fee_rate() / FEE_HISTOGRAM_BUCKET) as u64
+    }
+
+    fn add(&mut self, transaction: &Transaction) {
+        let (count, vsize) = self.buckets.entry(Self::bucket(transaction)).or_default();
+        *count += 1;
+        *vsize += transaction.vsize();
+    }
+
+    fn remove(&mut self, transaction: &Transaction) {
+        let bucket = Self::bucket(transaction);
+        if let Some((count, vsize)) = self.buckets.get_mut(&bucket) {
+            *count -= 1;
+            *vsize -= transaction.vsize();
+            if *count == 0 {
+                self.buckets.remove(&bucket);
+            }
+        }
+    }
+
+    /// Returns the fee rate that ranks ahead of every bucket that would
+    /// overflow `capacity` bytes, or 0 if everything pending fits
+    fn rate_to_fit(&self, capacity: usize) -> f64 {
+        let mut queued = 0;
+        for (&bucket, &(_, vsize)) in self.buckets.iter().rev() {
+            queued += vsize;
+            if queued > capacity {
+                return (bucket + 1) as f64 * FEE_HISTOGRAM_BUCKET;
+            }
+        }
+        0.0
+    }
+
+    /// Lists each non-empty bucket's lowest fee rate and transaction count,
+    /// highest rate first
+    fn counts(&self) -> Vec<(f64, usize)> {
+        self.buckets
+            .iter()
+            .rev()
+            .map(|(&bucket, &(count, _))| (bucket as f64 * FEE_HISTOGRAM_BUCKET, count))
+            .collect()
+    }
+}
+
+/// Hex-encoded transaction hash
+type TxId = String;
+
+/// A transaction to be admitted as part of a batch; the nonce is assigned
+/// automatically when left empty
+#[derive(Debug, Clone)]
+struct TransactionRequest {
+    sender: Address,
+    outputs: Vec<Output>,
+    fee: f64,
+    lock_time: i64,
+    nonce: Option<u64>,
+}
+
+/// Reasons a transaction can be refused entry to the pending list
+#[derive(Debug, Clone, PartialEq)]
+enum MempoolError {
+    /// The raw bytes are not a single serialized transaction
+    Malformed,
+    /// The transaction pays no one and carries no asset or name operation
+    NoOutputs,
+    /// The sender or a recipient is empty
+    MissingAddress,
+    /// The sender is `"0"`, which only a block's coinbase may use
+    ReservedSender,
+    /// An output amount is not a positive, finite number
+    InvalidAmount,
+    /// The fee is negative or not finite
+    InvalidFee,
+    /// The fee is below the relay policy's minimum of `required` and the
+    /// transaction carries no valid stamp
+    FeeTooLow { required: f64 },
+    /// An output is smaller than the relay policy's dust threshold
+    DustOutput,
+    /// The sender already has the relay policy's maximum number of transactions pending
+    TooManyPending,
+    /// The sender's pending transactions would exceed the relay policy's maximum value
+    PendingValueTooHigh,
+    /// The sender's confirmed balance, less what its other pending
+    /// transactions spend, does not cover the outputs and fee
+    InsufficientFunds,
+    /// The sender already used this nonce in a mined transaction; `next_nonce`
+    /// is the lowest one still free
+    NonceTooLow { next_nonce: u64 },
+    /// A transaction with the same sender and nonce is pending and replacement is disabled
+    Conflict,
+    /// The replacement does not pay at least `required` in fees
+    ReplacementFeeTooLow { required: f64 },
+    /// The transaction was valid but another item in its batch was rejected
+    BatchAborted,
+    /// The asset being transferred or burned does not exist
+    UnknownAsset,
+    /// The sender does not own the asset being transferred or burned
+    NotAssetOwner,
+    /// Another pending transaction already operates on the asset
+    AssetBusy,
+    /// The name is empty, too long or uses characters other than a-z, 0-9 and '-'
+    InvalidName,
+    /// The name is registered to someone else and has not expired
+    NameTaken,
+    /// The name being renewed or transferred is not registered or has expired
+    UnknownName,
+    /// The sender does not own the name being renewed or transferred
+    NotNameOwner,
+    /// Another pending transaction already operates on the name
+    NameBusy,
+}
+
+impl MempoolError {
+    /// Returns a stable, machine-readable name for the reason
+    fn code(&self) -> &'static str {
+        match self {
+            MempoolError::Malformed => "malformed",
+            MempoolError::NoOutputs => "no-outputs",
+            MempoolError::MissingAddress => "missing-address",
+            MempoolError::ReservedSender => "reserved-sender",
+            MempoolError::InvalidAmount => "invalid-amount",
+            MempoolError::InvalidFee => "invalid-fee",
+            MempoolError::FeeTooLow { .. } => "low-fee",
+            MempoolError::DustOutput => "dust",
+            MempoolError::TooManyPending => "too-many-pending",
+            MempoolError::PendingValueTooHigh => "pending-value-too-high",
+            MempoolError::InsufficientFunds => "insufficient-funds",
+            MempoolError::NonceTooLow { .. } => "nonce-too-low",
+            MempoolError::Conflict => "conflict",
+            MempoolError::ReplacementFeeTooLow { .. } => "replacement-fee-too-low",
+            MempoolError::BatchAborted => "batch-aborted",
+            MempoolError::UnknownAsset => "unknown-asset",
+            MempoolError::NotAssetOwner => "not-asset-owner",
+            MempoolError::AssetBusy => "asset-busy",
+            MempoolError::InvalidName => "invalid-name",
+            MempoolError::NameTaken => "name-taken",
+            MempoolError::UnknownName => "unknown-name",
+            MempoolError::NotNameOwner => "not-name-owner",
+            MempoolError::NameBusy => "name-busy",
+        }
+    }
+}
+
+/// Notable changes to the chain or pending transactions, collected until
+/// drained with `Blockchain::take_events`
+#[derive(Debug, Clone, PartialEq)]
+enum ChainEvent {
+    /// A pending transaction was evicted in favour of a higher-fee conflict
+    TransactionReplaced { replaced: Box<Transaction>, replacement: Box<Transaction> },
+    /// A watched transaction reached its target number of confirmations
+    TransactionConfirmed { txid: TxId, confirmations: u64 },
+    /// A reorg pushed a watched transaction back below its target
+    ConfirmationsDropped { txid: TxId, confirmations: u64 },
+    /// A scheduled payment came due and was added to the pending list
+    ScheduledPaymentSubmitted { schedule_id: u64, txid: TxId },
+}
+
+/// Where a transaction stands relative to the current tip and pending list
+#[derive(Debug, Clone, PartialEq)]
+enum TransactionStatus {
+    /// Waiting in the pending list
+    Pending,
+    /// Mined, with the including block counted as one confirmation
+    Confirmed { confirmations: u64 },
+    /// Another transaction with the same sender and nonce was mined or is
+    /// pending instead, so this one can never confirm
+    Conflicted { by: TxId },
+    /// Neither mined nor pending, and its nonce is still free, so it may be
+    /// resubmitted
+    Dropped,
+}
+
+/// Which pending transactions `Blockchain::get_mempool` lists; unset fields
+/// match everything
+#[derive(Debug, Clone, Default)]
+struct MempoolFilter {
+    sender: Option<Address>,
+    /// Matches transactions with at least one output to this address
+    recipient: Option<Address>,
+    min_fee_rate: f64,
+}
+
+impl MempoolFilter {
+    fn matches(&self, transaction: &Transaction) -> bool {
+        self.sender.as_ref().is_none_or(|sender| transaction.sender == *sender)
+            && self
+                .recipient
+                .as_ref()
+                .is_none_or(|recipient| transaction.outputs.iter().any(|output| output.recipient == *recipient))
+            && transaction.fee_rate() >= self.min_fee_rate
+    }
+}
+
+/// A zero-based page of `size` entries
+#[derive(Debug, Clone, Copy)]
+struct Page {
+    number: usize,
+    size: usize,
+}
+
+/// One pending transaction with the figures needed to see why it is waiting
+#[derive(Debug, Clone)]
+struct MempoolEntry {
+    txid: TxId,
+    transaction: Transaction,
+    vsize: usize,
+    fee_rate: f64,
+    /// Seconds since the transaction was admitted to the pending list
+    age: i64,
+    /// Pending transactions from the same sender with lower nonces, which
+    /// must be mined first
+    ancestors: usize,
+    /// Pending transactions from the same sender with higher nonces, which
+    /// wait on this one
+    descendants: usize,
+    /// The pending transaction using the sender's previous nonce, if any
+    depends_on: Option<TxId>,
+}
+
+/// Totals over every pending transaction a filter matched, not just one page
+#[derive(Debug, Clone, Default, PartialEq)]
+struct MempoolSummary {
+    count: usize,
+    total_vsize: usize,
+    total_fees: f64,
+    min_fee_rate: f64,
+    median_fee_rate: f64,
+    max_fee_rate: f64,
+}
+
+/// One page of `Blockchain::get_mempool`, highest fee rate first
+#[derive(Debug, Clone)]
+struct MempoolView {
+    entries: Vec<MempoolEntry>,
+    summary: MempoolSummary,
+}
+
+/// When a scheduled payment is submitted
+#[derive(Debug, Clone)]
+enum PaymentTrigger {
+    /// Every `interval` blocks until cancelled, next at `next_height`
+    Recurring { interval: u64, next_height: u64 },
+    /// Once, after the sender has sent nothing for `blocks` blocks since
+    /// `since` or its last mined transaction, whichever is later
+    Inactivity { blocks: u64, since: u64 },
+}
+
+/// A payment the node holds and submits on the sender's behalf
+#[derive(Debug, Clone)]
+struct ScheduledPayment {
+    id: u64,
+    sender: Address,
+    outputs: Vec<Output>,
+    fee: f64,
+    trigger: PaymentTrigger,
+}
+
+/// A transaction whose confirmations are followed by `Blockchain::watch_transaction`
+#[derive(Debug, Clone)]
+struct WatchedTransaction {
+    txid: TxId,
+    target: u64,
+    /// Whether `TransactionConfirmed` has been emitted and not since revoked
+    reached: bool,
+}
+
+/// Reasons `Blockchain::replace_chain` can refuse a competing chain
+#[derive(Debug, Clone, PartialEq)]
+enum ReorgError {
+    /// The competing chain is not longer than the current one
+    NotLonger,
+    /// The competing chain would roll back blocks at or below the finalized height
+    ForkBelowFinality { fork_height: u64, finalized_height: u64 },
+    /// The competing chain adds more than `MAX_NEW_BLOCKS_PER_OFFER` blocks
+    TooManyBlocks,
+    /// The competing chain starts from another genesis block
+    WrongGenesis,
+    /// A block of the competing chain fails validation
+    InvalidBlock(InvalidBlock),
+}
+
+impl ReorgError {
+    /// Returns a stable, machine-readable name for the reason
+    fn code(&self) -> &'static str {
+        match self {
+            ReorgError::NotLonger => "not-longer",
+            ReorgError::ForkBelowFinality { .. } => "fork-below-finality",
+            ReorgError::TooManyBlocks => "too-many-blocks",
+            ReorgError::WrongGenesis => "wrong-genesis",
+            ReorgError::InvalidBlock(invalid) => invalid.reason.code(),
+        }
+    }
+}
+
+/// An address's balance and the nonce its next transaction should use
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct AccountState {
+    balance: f64,
+    next_nonce: u64,
+}
+
+impl AccountState {
+    /// Checks whether the balance covers `amount`, comparing whole smallest
+    /// units so rounding error in the running balance cannot refuse an exact
+    /// spend
+    fn can_afford(&self, amount: f64) -> bool {
+        Amount::nearest(self.balance) >= Amount::nearest(amount)
+    }
+}
+
+/// One address's state before and after a range of blocks, reported by
+/// `Blockchain::state_diff`
+#[derive(Debug, Clone, PartialEq)]
+struct AccountChange {
+    address: Address,
+    before: AccountState,
+    after: AccountState,
+}
+
+/// An asset's owner before and after a transaction, `None` while the asset
+/// does not exist
+#[derive(Debug, Clone, PartialEq)]
+struct AssetChange {
+    asset_id: String,
+    before: Option<Address>,
+    after: Option<Address>,
+}
+
+/// A name's registration before and after a transaction, `None` while the
+/// name is unregistered
+#[derive(Debug, Clone, PartialEq)]
+struct NameChange {
+    name: String,
+    before: Option<NameRecord>,
+    after: Option<NameRecord>,
+}
+
+/// Every write a mined transaction made, reported by `Blockchain::trace_transaction`
+#[derive(Debug, Clone, PartialEq)]
+struct TransactionTrace {
+    /// Touched accounts, sender first
+    accounts: Vec<AccountChange>,
+    asset: Option<AssetChange>,
+    name: Option<NameChange>,
+}
+
+/// Summary counts reported by `Blockchain::stats`
+#[derive(Debug, Clone, PartialEq)]
+struct ChainStats {
+    height: u64,
+    transactions: usize,
+    /// Serialized size of every block on the main chain, in bytes
+    size: usize,
+    stale_blocks: usize,
+}
+
+/// Debits the sender's outputs and fee, credits each recipient and advances
+/// the sender's nonce
+///
+/// Coins sent by the `"0"` address are newly minted, so it has no account of its own.
+fn apply_payment(accounts: &mut HashMap<Address, AccountState>, transaction: &Transaction) {
+    if transaction.sender != "0" {
+        let sender = accounts.entry(transaction.sender.clone()).or_default();
+        sender.balance -= transaction.spend();
+        sender.next_nonce = sender.next_nonce.max(transaction.nonce + 1);
+    }
+    for output in &transaction.outputs {
+        accounts.entry(output.recipient.clone()).or_default().balance += output.amount;
+    }
+}
+
+/// Hashes every account in address order, so two nodes agree on the root
+/// exactly when they agree on every balance and nonce
+fn state_root(accounts: &HashMap<Address, AccountState>) -> String {
+    let mut addresses: Vec<&Address> = accounts.keys().collect();
+    addresses.sort();
+    let mut hasher = Sha256::new();
+    for address in addresses {
+        let mut buffer = Vec::new();
+        encode_str(&mut buffer, address);
+        buffer.extend(accounts[address].balance.to_le_bytes());
+        buffer.extend(accounts[address].next_nonce.to_le_bytes());
+        hasher.update(buffer);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Returns the median timestamp of the last `MEDIAN_TIME_SPAN` blocks
+fn median_timestamp(blocks: &[Block]) -> i64 {
+    let start = blocks.len().saturating_sub(MEDIAN_TIME_SPAN);
+    median_of(blocks[start..].iter().map(|block| block.timestamp))
+}
+
+/// Returns the median of a non-empty set of timestamps
+fn median_of(timestamps: impl Iterator<Item = i64>) -> i64 {
+    let mut timestamps: Vec<i64> = timestamps.collect();
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/// A set of block hashes that forgets the one inserted first once it holds
+/// more than `capacity`
+#[derive(Debug)]
+struct BlockHashCache {
+    capacity: usize,
+    hashes: HashSet<String>,
+    /// The hashes in insertion order, oldest first
+    order: VecDeque<String>,
+}
+
+impl BlockHashCache {
+    fn new(capacity: usize) -> Self {
+        BlockHashCache { capacity, hashes: HashSet::new(), order: VecDeque::new() }
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    fn insert(&mut self, hash: String) {
+        if !self.hashes.insert(hash.clone()) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Represents the blockchain
+struct Blockchain {
+    chain: Vec<Block>,
+    current_transactions: Vec<Transaction>,
+    /// Fee rates of `current_transactions`, kept in step with every change to it
+    fee_histogram: FeeHistogram,
+    /// When each pending transaction was admitted, by txid
+    pending_since: HashMap<TxId, i64>,
+    clock: Box<dyn Clock>,
+    /// Hashes of blocks that already passed the proof, timestamp and lock time checks
+    validated_blocks: RefCell<BlockHashCache>,
+    /// Hashes of blocks that failed the proof check, which can never start passing
+    rejected_blocks: RefCell<BlockHashCache>,
+    /// Account states after every `STATE_SNAPSHOT_INTERVAL`th block, keyed by
+    /// that block's hash so a reorg cannot serve a stale snapshot
+    state_snapshots: RefCell<HashMap<String, HashMap<Address, AccountState>>>,
+    /// Blocks with valid proofs that arrived after another block took their height
+    stale_blocks: Vec<Block>,
+    replacement_policy: ReplacementPolicy,
+    relay_policy: RelayPolicy,
+    events: Vec<ChainEvent>,
+    watched_transactions: Vec<WatchedTransaction>,
+    scheduled_payments: Vec<ScheduledPayment>,
+    next_schedule_id: u64,
+}
+
+impl Blockchain {
+    /// Creates a new blockchain with a genesis block
+    fn new() -> Self {
+        Self::with_clock(Box::new(SystemClock))
+    }
+
+    /// Creates a new blockchain whose timestamps come from the given clock
+    fn with_clock(clock: Box<dyn Clock>) -> Self {
+        Blockchain {
+            chain: vec![Block::new(
+                0,
+                VERSION_BITS_TOP,
+                clock.now(),
+                Vec::new(),
+                100,
+                String::from("0"),
+                state_root(&HashMap::new()),
+            )],
+            current_transactions: Vec::new(),
+            fee_histogram: FeeHistogram::default(),
+            pending_since: HashMap::new(),
+            clock,
+            validated_blocks: RefCell::new(BlockHashCache::new(BLOCK_CACHE_CAPACITY)),
+            rejected_blocks: RefCell::new(BlockHashCache::new(BLOCK_CACHE_CAPACITY)),
+            state_snapshots: RefCell::new(HashMap::new()),
+            stale_blocks: Vec::new(),
+            replacement_policy: ReplacementPolicy::default(),
+            relay_policy: RelayPolicy::default(),
+            events: Vec::new(),
+            watched_transactions: Vec::new(),
+            scheduled_payments: Vec::new(),
+            next_schedule_id: 0,
+        }
+    }
+
+    /// Adds a new transaction to the list of current transactions, paying the
+    /// minimum relay fee
+    fn new_transaction(&mut self, sender: Address, recipient: Address, amount: f64) -> Result<usize, MempoolError> {
+        self.new_timelocked_transaction(sender, recipient, amount, 0)
+    }
+
+    /// Adds a transaction that cannot be mined until the chain's median time
+    /// past has passed `lock_time`, paying the minimum relay fee
+    fn new_timelocked_transaction(
+        &mut self,
+        sender: Address,
+        recipient: Address,
+        amount: f64,
+        lock_time: i64,
+    ) -> Result<usize, MempoolError> {
+        let nonce = self.next_nonce(&sender);
+        let outputs = vec![Output::new(recipient, amount)];
+        self.submit_with_min_fee(Transaction { sender, outputs, lock_time, nonce, fee: 0.0, asset: None, name: None, stamp: 0 })
+    }
+
+    /// Adds a single transaction paying several recipients, such as a payroll
+    /// run, paying the minimum relay fee
+    fn new_payout_transaction(&mut self, sender: Address, outputs: Vec<Output>) -> Result<usize, MempoolError> {
+        let nonce = self.next_nonce(&sender);
+        self.submit_with_min_fee(Transaction { sender, outputs, lock_time: 0, nonce, fee: 0.0, asset: None, name: None, stamp: 0 })
+    }
+
+    /// Returns the lowest fee at which the transaction is both relayed by this
+    /// node and allowed into a block
+    fn min_fee(&self, transaction: &Transaction) -> f64 {
+        self.relay_policy.min_fee(transaction).max(RelayPolicy::CONSENSUS.min_fee(transaction))
+    }
+
+    /// Sets the transaction's fee to the lowest this node relays and blocks
+    /// accept, and submits it
+    fn submit_with_min_fee(&mut self, mut transaction: Transaction) -> Result<usize, MempoolError> {
+        transaction.fee = self.min_fee(&transaction);
+        self.submit_transaction(transaction)
+    }
+
+    /// Returns the nonce the sender's next transaction should use
+    fn next_nonce(&self, sender: &str) -> u64 {
+        self.chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .chain(&self.current_transactions)
+            .filter(|transaction| transaction.sender == sender)
+            .map(|transaction| transaction.nonce + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the address's balance and next nonce at the tip, ignoring the
+    /// pending list
+    fn confirmed_state(&self, address: &str) -> AccountState {
+        self.account_states(self.last_block().index).get(address).copied().unwrap_or_default()
+    }
+
+    /// Adds a fully specified transaction to the pending list
+    ///
+    /// If a pending transaction has the same sender and nonce, the new one
+    /// replaces it when the replacement policy allows and its fee is high
+    /// enough, and a `ChainEvent::TransactionReplaced` is recorded.
+    fn submit_transaction(&mut self, transaction: Transaction) -> Result<usize, MempoolError> {
+        let admitted = self.check_admission(&transaction)?;
+        self.pending_since.insert(transaction.hash(), self.clock.now());
+        match admitted {
+            Some(position) => {
+                let replaced = std::mem::replace(&mut self.current_transactions[position], transaction.clone());
+                self.pending_since.remove(&replaced.hash());
+                self.fee_histogram.remove(&replaced);
+                self.fee_histogram.add(&transaction);
+                self.events.push(ChainEvent::TransactionReplaced {
+                    replaced: Box::new(replaced),
+                    replacement: Box::new(transaction),
+                });
+            }
+            None => {
+                self.fee_histogram.add(&transaction);
+                self.current_transactions.push(transaction);
+            }
+        }
+        Ok(self.last_block().index as usize + 1)
+    }
+
+    /// Runs every check `submit_transaction` would on a serialized
+    /// transaction without admitting it, so wallets can find out why it would
+    /// be refused before sending it
+    fn validate_transaction(&self, raw: &[u8]) -> Result<TxId, MempoolError> {
+        let view = TransactionRef::parse(raw).ok_or(MempoolError::Malformed)?;
+        // The first check `Transaction::check` makes, answered without copying
+        if view.output_count == 0 && !view.has_asset_operation() && !view.has_name_operation() {
+            return Err(MempoolError::NoOutputs);
+        }
+        let transaction = view.to_transaction();
+        self.check_admission(&transaction)?;
+        Ok(transaction.hash())
+    }
+
+    /// Checks a transaction against the relay policy and pending list, and
+    /// returns the position of the pending transaction it would replace
+    fn check_admission(&self, transaction: &Transaction) -> Result<Option<usize>, MempoolError> {
+        transaction.check()?;
+        if transaction.sender == "0" {
+            return Err(MempoolError::ReservedSender);
+        }
+        let confirmed = self.confirmed_state(&transaction.sender);
+        if transaction.nonce < confirmed.next_nonce {
+            return Err(MempoolError::NonceTooLow { next_nonce: confirmed.next_nonce });
+        }
+        RelayPolicy::CONSENSUS.check(transaction)?;
+        self.relay_policy.check(transaction)?;
+        self.check_sender_limits(transaction)?;
+        let pending: f64 = self
+            .current_transactions
+            .iter()
+            .filter(|pending| pending.sender == transaction.sender && pending.nonce != transaction.nonce)
+            .map(Transaction::spend)
+            .sum();
+        if !confirmed.can_afford(pending + transaction.spend()) {
+            return Err(MempoolError::InsufficientFunds);
+        }
+        if let Some(asset_id) = transaction.asset.as_ref().and_then(AssetOperation::asset_id) {
+            self.asset_registry().check(transaction)?;
+            let busy = self.current_transactions.iter().any(|pending| {
+                let replaced = pending.sender == transaction.sender && pending.nonce == transaction.nonce;
+                !replaced && pending.asset.as_ref().and_then(AssetOperation::asset_id) == Some(asset_id)
+            });
+            if busy {
+                return Err(MempoolError::AssetBusy);
+            }
+        }
+        if let Some(operation) = &transaction.name {
+            self.name_registry().check(transaction, self.chain.len() as u64)?;
+            let busy = self.current_transactions.iter().any(|pending| {
+                let replaced = pending.sender == transaction.sender && pending.nonce == transaction.nonce;
+                !replaced && pending.name.as_ref().map(NameOperation::name) == Some(operation.name())
+            });
+            if busy {
+                return Err(MempoolError::NameBusy);
+            }
+        }
+        let conflict = self
+            .current_transactions
+            .iter()
+            .position(|pending| pending.sender == transaction.sender && pending.nonce == transaction.nonce);
+        if let Some(position) = conflict {
+            if !self.replacement_policy.enabled {
+                return Err(MempoolError::Conflict);
+            }
+            let required = self.current_transactions[position].fee + self.replacement_policy.min_fee_increase;
+            if transaction.fee < required {
+                return Err(MempoolError::ReplacementFeeTooLow { required });
+            }
+        }
+        Ok(conflict)
+    }
+
+    /// Checks that admitting the transaction keeps its sender within the relay
+    /// policy's pending count and value limits; a transaction it would replace
+    /// does not count
+    fn check_sender_limits(&self, transaction: &Transaction) -> Result<(), MempoolError> {
+        let pending: Vec<&Transaction> = self
+            .current_transactions
+            .iter()
+            .filter(|pending| pending.sender == transaction.sender && pending.nonce != transaction.nonce)
+            .collect();
+        if pending.len() >= self.relay_policy.max_pending_transactions {
+            return Err(MempoolError::TooManyPending);
+        }
+        let pending_value: f64 = pending.iter().map(|pending| pending.spend()).sum();
+        if pending_value + transaction.spend() > self.relay_policy.max_pending_value {
+            return Err(MempoolError::PendingValueTooHigh);
+        }
+        Ok(())
+    }
+
+    /// Admits a batch of transactions as a unit, returning each item's txid
+    ///
+    /// If any item is rejected, none of the batch is admitted: failing items
+    /// report their own error and the rest report `MempoolError::BatchAborted`.
+    fn new_transactions(&mut self, batch: Vec<TransactionRequest>) -> Vec<Result<TxId, MempoolError>> {
+        let pending = self.current_transactions.clone();
+        let pending_since = self.pending_since.clone();
+        let fee_histogram = self.fee_histogram.clone();
+        let events = self.events.len();
+        let results: Vec<Result<TxId, MempoolError>> = batch
+            .into_iter()
+            .map(|request| {
+                let nonce = request.nonce.unwrap_or_else(|| self.next_nonce(&request.sender));
+                let transaction = Transaction {
+                    sender: request.sender,
+                    outputs: request.outputs,
+                    lock_time: request.lock_time,
+                    nonce,
+                    fee: request.fee,
+                    asset: None,
+                    name: None,
+                    stamp: 0,
+                };
+                let txid = transaction.hash();
+                self.submit_transaction(transaction).map(|_| txid)
+            })
+            .collect();
+        if results.iter().all(Result::is_ok) {
+            return results;
+        }
+        self.current_transactions = pending;
+        self.pending_since = pending_since;
+        self.fee_histogram = fee_histogram;
+        self.events.truncate(events);
+        results
+            .into_iter()
+            .map(|result| result.and(Err(MempoolError::BatchAborted)))
+            .collect()
+    }
+
+    /// Emits `TransactionConfirmed` once the transaction has `target`
+    /// confirmations, and `ConfirmationsDropped` if a reorg later takes them away
+    fn watch_transaction(&mut self, txid: TxId, target: u64) {
+        self.watched_transactions.push(WatchedTransaction { txid, target, reached: false });
+        self.update_watched_transactions();
+    }
+
+    /// Registers a payment that is submitted every `interval` blocks, starting
+    /// `interval` blocks from now, and returns its schedule id
+    fn schedule_payment(&mut self, sender: Address, outputs: Vec<Output>, fee: f64, interval: u64) -> Result<u64, MempoolError> {
+        let interval = interval.max(1);
+        let next_height = self.last_block().index + interval;
+        self.add_scheduled_payment(sender, outputs, fee, PaymentTrigger::Recurring { interval, next_height })
+    }
+
+    /// Registers a dead man's switch: a payment submitted once `sender` has
+    /// been inactive for `inactivity_blocks` blocks, and returns its schedule id
+    fn schedule_inheritance(
+        &mut self,
+        sender: Address,
+        outputs: Vec<Output>,
+        fee: f64,
+        inactivity_blocks: u64,
+    ) -> Result<u64, MempoolError> {
+        let since = self.last_block().index;
+        self.add_scheduled_payment(sender, outputs, fee, PaymentTrigger::Inactivity { blocks: inactivity_blocks, since })
+    }
+
+    /// Validates and stores a scheduled payment, returning its id
+    fn add_scheduled_payment(
+        &mut self,
+        sender: Address,
+        outputs: Vec<Output>,
+        fee: f64,
+        trigger: PaymentTrigger,
+    ) -> Result<u64, MempoolError> {
+        let transaction = Transaction {
+            sender: sender.clone(),
+            outputs: outputs.clone(),
+            lock_time: 0,
+            nonce: 0,
+            fee,
+            asset: None,
+            name: None,
+            stamp: 0,
+        };
+        transaction.check()?;
+        if transaction.sender == "0" {
+            return Err(MempoolError::ReservedSender);
+        }
+        RelayPolicy::CONSENSUS.check(&transaction)?;
+        self.relay_policy.check(&transaction)?;
+        let id = self.next_schedule_id;
+        self.next_schedule_id += 1;
+        self.scheduled_payments.push(ScheduledPayment { id, sender, outputs, fee, trigger });
+        Ok(id)
+    }
+
+    /// Returns the height of the last block containing a transaction sent by `address`
+    fn last_activity(&self, address: &str) -> Option<u64> {
+        self.chain
+            .iter()
+            .rev()
+            .find(|block| block.transactions.iter().any(|transaction| transaction.sender == address))
+            .map(|block| block.index)
+    }
+
+    /// Cancels a scheduled payment, returning whether it was registered
+    fn cancel_scheduled_payment(&mut self, schedule_id: u64) -> bool {
+        let count = self.scheduled_payments.len();
+        self.scheduled_payments.retain(|payment| payment.id != schedule_id);
+        self.scheduled_payments.len() != count
+    }
+
+    /// Submits every scheduled payment that has come due, dropping one-off
+    /// payments once they have been sent. A one-off payment the mempool refuses
+    /// stays scheduled and is retried after the next block.
+    fn submit_scheduled_payments(&mut self) {
+        let height = self.last_block().index;
+        let mut scheduled = std::mem::take(&mut self.scheduled_payments);
+        scheduled.retain_mut(|payment| {
+            let (due, keep) = match &mut payment.trigger {
+                PaymentTrigger::Recurring { interval, next_height } => {
+                    let due = *next_height <= height;
+                    while *next_height <= height {
+                        *next_height += *interval;
+                    }
+                    (due, true)
+                }
+                PaymentTrigger::Inactivity { blocks, since } => {
+                    let last_active = self.last_activity(&payment.sender).unwrap_or(0).max(*since);
+                    let due = height - last_active >= *blocks;
+                    (due, !due)
+                }
+            };
+            if due {
+                let transaction = Transaction {
+                    sender: payment.sender.clone(),
+                    outputs: payment.outputs.clone(),
+                    lock_time: 0,
+                    nonce: self.next_nonce(&payment.sender),
+                    fee: payment.fee,
+                    asset: None,
+                    name: None,
+                    stamp: 0,
+                };
+                let txid = transaction.hash();
+                if self.submit_transaction(transaction).is_err() {
+                    return true;
+                }
+                self.events.push(ChainEvent::ScheduledPaymentSubmitted { schedule_id: payment.id, txid });
+            }
+            keep
+        });
+        scheduled.append(&mut self.scheduled_payments);
+        self.scheduled_payments = scheduled;
+    }
+
+    /// Runs the bookkeeping that follows every change to the chain
+    fn chain_updated(&mut self) {
+        self.update_watched_transactions();
+        self.submit_scheduled_payments();
+    }
+
+    /// Re-evaluates watched transactions after the chain changed
+    fn update_watched_transactions(&mut self) {
+        let mut watched = std::mem::take(&mut self.watched_transactions);
+        for watch in &mut watched {
+            let confirmations = self.confirmations(&watch.txid);
+            let reached = confirmations >= watch.target;
+            if reached == watch.reached {
+                continue;
+            }
+            watch.reached = reached;
+            let txid = watch.txid.clone();
+            self.events.push(if reached {
+                ChainEvent::TransactionConfirmed { txid, confirmations }
+            } else {
+                ChainEvent::ConfirmationsDropped { txid, confirmations }
+            });
+        }
+        self.watched_transactions = watched;
+    }
+
+    /// Removes and returns the events recorded since the last call
+    fn take_events(&mut self) -> Vec<ChainEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Returns the pending transactions with a lower nonce from the same
+    /// sender, all of which must be mined before `transaction`
+    fn pending_ancestors(&self, transaction: &Transaction) -> Vec<usize> {
+        self.current_transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| pending.sender == transaction.sender && pending.nonce < transaction.nonce)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Chooses pending transactions for the next block
+    ///
+    /// Each candidate is evaluated together with its unselected ancestors as a
+    /// package, and the package with the highest fee per byte is taken first, so
+    /// a high-fee child can pull its low-fee parent into the block. A package
+    /// must continue its sender's nonces without a gap, and the sender's
+    /// balance after the packages already chosen must cover it. Packages that would
+    /// push the block past `MAX_BLOCK_VSIZE`, with `coinbase_vsize` bytes already
+    /// taken by the coinbase, or that hold a transaction the consensus fee rules
+    /// refuse, are skipped.
+    fn select_transactions(&self, median_time_past: i64, coinbase_vsize: usize) -> Vec<Transaction> {
+        let assets = self.asset_registry();
+        let names = self.name_registry();
+        let height = self.chain.len() as u64;
+        let vsizes: Vec<usize> = self.current_transactions.iter().map(Transaction::vsize).collect();
+        let mut selected = vec![false; self.current_transactions.len()];
+        let mut accounts = self.account_states(self.last_block().index);
+        let mut order = Vec::new();
+        let mut block_vsize = coinbase_vsize;
+        loop {
+            let mut best: Option<(f64, Vec<usize>)> = None;
+            for (index, candidate) in self.current_transactions.iter().enumerate() {
+                if selected[index] {
+                    continue;
+                }
+                let sender = accounts.get(&candidate.sender).copied().unwrap_or_default();
+                let next_nonce = sender.next_nonce;
+                let mut package: Vec<usize> = self
+                    .pending_ancestors(candidate)
+                    .into_iter()
+                    .filter(|&ancestor| !selected[ancestor] && self.current_transactions[ancestor].nonce >= next_nonce)
+                    .collect();
+                package.push(index);
+                package.sort_by_key(|&member| self.current_transactions[member].nonce);
+                let spendable = package
+                    .iter()
+                    .zip(next_nonce..)
+                    .all(|(&member, nonce)| self.current_transactions[member].nonce == nonce)
+                    && sender.can_afford(package.iter().map(|&member| self.current_transactions[member].spend()).sum());
+                let package_vsize: usize = package.iter().map(|&member| vsizes[member]).sum();
+                if !spendable
+                    || block_vsize + package_vsize > MAX_BLOCK_VSIZE
+                    || !package.iter().all(|&member| {
+                        let transaction = &self.current_transactions[member];
+                        transaction.is_final(median_time_past)
+                            && RelayPolicy::CONSENSUS.check(transaction).is_ok()
+                            && assets.check(transaction).is_ok()
+                            && names.check(transaction, height).is_ok()
+                    })
+                {
+                    continue;
+                }
+                let fees: f64 = package.iter().map(|&member| self.current_transactions[member].fee).sum();
+                let fee_rate = fees / package_vsize as f64;
+                if best.as_ref().is_none_or(|(best_rate, _)| fee_rate > *best_rate) {
+                    best = Some((fee_rate, package));
+                }
+            }
+            let Some((_, package)) = best else {
+                break;
+            };
+            for member in package {
+                apply_payment(&mut accounts, &self.current_transactions[member]);
+                selected[member] = true;
+                block_vsize += vsizes[member];
+                order.push(member);
+            }
+        }
+        order.into_iter().map(|index| self.current_transactions[index].clone()).collect()
+    }
+
+    /// Removes mined transactions from the pending list
+    fn remove_pending(&mut self, transactions: &[Transaction]) {
+        for transaction in transactions {
+            if let Some(position) = self.current_transactions.iter().position(|pending| pending == transaction) {
+                self.current_transactions.remove(position);
+                self.pending_since.remove(&transaction.hash());
+                self.fee_histogram.remove(transaction);
+            }
+        }
+    }
+
+    /// Creates a new block paying the reward to `miner_address` and adds it to
+    /// the chain
+    ///
+    /// The block is built like a template, so it starts with the coinbase and
+    /// its transactions are chosen by `select_transactions`; any left over,
+    /// such as those whose lock time has not yet passed, stay pending.
+    fn new_block(&mut self, miner_address: &str, proof: u64) -> Block {
+        let block = self.build_block_template(miner_address).into_block(proof);
+        self.connect_block(block)
+    }
+
+    /// Appends a block built on the tip, removing its transactions from the
+    /// pending list
+    fn connect_block(&mut self, block: Block) -> Block {
+        self.remove_pending(&block.transactions);
+        self.chain.push(block.clone());
+        self.chain_updated();
+        block
+    }
+
+    /// Builds a block template paying the reward and fees to `miner_address`,
+    /// filled by `select_transactions`
+    fn build_block_template(&self, miner_address: &str) -> BlockTemplate {
+        let last_block = self.last_block();
+        let median_time_past = self.median_time_past();
+        let mut coinbase = Transaction {
+            sender: Address::from("0"),
+            outputs: vec![Output::new(Address::from(miner_address), MINING_REWARD)],
+            lock_time: 0,
+            // Committing to the height satisfies `COINBASE_HEIGHT`
+            nonce: last_block.index + 1,
+            fee: 0.0,
+            asset: None,
+            name: None,
+            stamp: 0,
+        };
+        // The fees collected do not change the coinbase's size
+        let transactions = self.select_transactions(median_time_past, coinbase.vsize());
+        coinbase.outputs[0].amount = block_reward(&transactions).to_coins();
+        let mut merkle_leaves = vec![coinbase.clone()];
+        merkle_leaves.extend(transactions.iter().cloned());
+        let state_root = self.state_root_after(&merkle_leaves);
+        let merkle_tree = MerkleTree::new(&merkle_leaves);
+        BlockTemplate {
+            index: self.chain.len() as u64,
+            version: self.next_block_version(),
+            timestamp: self.clock.now().max(median_time_past + 1),
+            previous_hash: last_block.calculate_hash(),
+            last_proof: last_block.proof,
+            target: String::from(DIFFICULTY_PREFIX),
+            coinbase,
+            transactions,
+            merkle_root: merkle_tree.root(),
+            merkle_tree,
+            state_root,
+        }
+    }
+
+    /// Appends the block described by a completed template, removing its
+    /// transactions from the pending list
+    ///
+    /// The block is held to the same rules as `check_chain` applies to the
+    /// blocks before it. A valid block built on an older tip lost the race for
+    /// its height and is recorded in `stale_blocks` instead.
+    fn submit_block(&mut self, template: BlockTemplate, proof: u64) -> Result<Block, SubmitBlockError> {
+        if !self.valid_proof(template.last_proof, proof) {
+            return Err(SubmitBlockError::InvalidProof);
+        }
+        let stale = template.previous_hash != self.last_block().calculate_hash();
+        let block = template.into_block(proof);
+        let parents = &self.chain[..(block.index as usize).min(self.chain.len())];
+        let checked = self.check_chain(parents.iter().chain([&block]));
+        if stale {
+            if checked.is_ok() {
+                self.stale_blocks.push(block);
+            }
+            return Err(SubmitBlockError::StaleTemplate);
+        }
+        checked.map_err(|invalid| SubmitBlockError::Rejected(invalid.reason))?;
+        Ok(self.connect_block(block))
+    }
+
+    /// Returns a reference to the last block in the chain
+    fn last_block(&self) -> &Block {
+        self.chain.last().unwrap()
+    }
+
+    /// Returns the state of a deployment for a block at `height`, which may be
+    /// at most the next block's
+    ///
+    /// States advance once per signalling window, based on how many blocks in
+    /// the previous window set the deployment's version bit. A deployment that
+    /// times out before it starts fails without being voted on.
+    fn deployment_state(&self, deployment: &Deployment, height: u64) -> DeploymentState {
+        let mut tracker = DeploymentTracker::new(*deployment);
+        for block in self.chain.iter().take(height as usize) {
+            tracker.push(block);
+        }
+        tracker.state
+    }
+
+    /// Returns the state of every known deployment for the next block
+    fn deployment_states(&self) -> Vec<(&'static str, DeploymentState)> {
+        let height = self.chain.len() as u64;
+        DEPLOYMENTS
+            .iter()
+            .map(|deployment| (deployment.name, self.deployment_state(deployment, height)))
+            .collect()
+    }
+
+    /// Builds the version for the next block, signalling every deployment that
+    /// is still being voted on
+    fn next_block_version(&self) -> u32 {
+        let height = self.chain.len() as u64;
+        DEPLOYMENTS
+            .iter()
+            .filter(|deployment| {
+                matches!(
+                    self.deployment_state(deployment, height),
+                    DeploymentState::Started | DeploymentState::LockedIn
+                )
+            })
+            .fold(VERSION_BITS_TOP, |version, deployment| version | (1 << deployment.bit))
+    }
+
+    /// Returns the median timestamp of the last 11 blocks, which the next
+    /// block's timestamp must exceed
+    fn median_time_past(&self) -> i64 {
+        median_timestamp(&self.chain)
+    }
+
+    /// Checks the hash links, proofs, timestamps, lock times, consensus fee rules,
+    /// asset ownership, name registrations and state roots of every block
+    fn is_valid_chain(&self) -> bool {
+        self.valid_chain(&self.chain)
+    }
+
+    /// Checks the hash links, proofs, timestamps, lock times, consensus fee rules,
+    /// asset ownership, name registrations and state roots of a chain,
+    /// starting from its genesis block
+    ///
+    /// Blocks are consumed one at a time. Apart from the replayed state, only
+    /// the previous block's hash and proof and the last `MEDIAN_TIME_SPAN`
+    /// timestamps are kept. Blocks can therefore be streamed from storage
+    /// rather than collected into a `Vec` first.
+    ///
+    /// Checks run from cheapest to most expensive, and validation stops at the
+    /// first failure. Index, hash link, size and well-formedness checks come
+    /// before the block is hashed. Proof and timestamp checks come before
+    /// account, asset and name state is replayed.
+    ///
+    /// A block's hash commits to its whole ancestry. Blocks that passed once
+    /// are remembered and only have their hash link re-checked on later calls.
+    /// Blocks with an invalid proof are remembered too, and are refused
+    /// without further work. Payments, asset and name operations are always
+    /// replayed, since they rebuild the state that later blocks are checked
+    /// against.
+    fn valid_chain<B: Borrow<Block>>(&self, blocks: impl IntoIterator<Item = B>) -> bool {
+        self.check_chain(blocks).is_ok()
+    }
+
+    /// Runs the same checks as `valid_chain`, reporting the first block to
+    /// fail and why
+    fn check_chain<B: Borrow<Block>>(&self, blocks: impl IntoIterator<Item = B>) -> Result<(), InvalidBlock> {
+        let mut blocks = blocks.into_iter();
+        let bad_genesis = InvalidBlock { height: 0, reason: BlockRejection::BadGenesis };
+        let genesis = blocks.next().ok_or_else(|| bad_genesis.clone())?;
+        let genesis = genesis.borrow();
+        let max_timestamp = self.clock.now() + MAX_FUTURE_BLOCK_TIME;
+        let mut previous_hash = genesis.calculate_hash();
+        let mut previous_proof = genesis.proof;
+        let mut recent_timestamps = VecDeque::from([genesis.timestamp]);
+        let mut coinbase_height = DeploymentTracker::new(COINBASE_HEIGHT);
+        coinbase_height.push(genesis);
+        let mut assets = AssetRegistry::default();
+        let mut names = NameRegistry::default();
+        let mut accounts = HashMap::new();
+        if genesis.state_root != state_root(&accounts) {
+            return Err(bad_genesis);
+        }
+        for (block, index) in blocks.zip(1..) {
+            let block = block.borrow();
+            let reject = |reason| InvalidBlock { height: index, reason };
+            if block.index != index {
+                return Err(reject(BlockRejection::WrongIndex));
+            }
+            if block.previous_hash != previous_hash {
+                return Err(reject(BlockRejection::StaleParent));
+            }
+            block.check().map_err(reject)?;
+            if coinbase_height.state == DeploymentState::Active && block.transactions[0].nonce != block.index {
+                return Err(reject(BlockRejection::BadCoinbase));
+            }
+            let hash = block.calculate_hash();
+            if self.rejected_blocks.borrow().contains(&hash) {
+                return Err(reject(BlockRejection::InvalidProof));
+            }
+            if !self.validated_blocks.borrow().contains(&hash) {
+                if !self.valid_proof(previous_proof, block.proof) {
+                    self.rejected_blocks.borrow_mut().insert(hash);
+                    return Err(reject(BlockRejection::InvalidProof));
+                }
+                let median_time_past = median_of(recent_timestamps.iter().copied());
+                if block.timestamp <= median_time_past || block.timestamp > max_timestamp {
+                    return Err(reject(BlockRejection::BadTimestamp));
+                }
+                for transaction in &block.transactions {
+                    if !transaction.is_final(median_time_past) {
+                        return Err(reject(BlockRejection::NotFinal));
+                    }
+                    RelayPolicy::CONSENSUS
+                        .check(transaction)
+                        .map_err(|error| reject(BlockRejection::FeeRules(error)))?;
+                }
+                self.validated_blocks.borrow_mut().insert(hash.clone());
+            }
+            for transaction in &block.transactions {
+                if transaction.sender != "0" {
+                    let sender: AccountState = accounts.get(&transaction.sender).copied().unwrap_or_default();
+                    if transaction.nonce != sender.next_nonce {
+                        return Err(reject(BlockRejection::BadNonce));
+                    }
+                    if !sender.can_afford(transaction.spend()) {
+                        return Err(reject(BlockRejection::InsufficientFunds));
+                    }
+                }
+                apply_payment(&mut accounts, transaction);
+                assets
+                    .apply(transaction)
+                    .and_then(|()| names.apply(transaction, block.index))
+                    .map_err(|error| reject(BlockRejection::InvalidOperation(error)))?;
+            }
+            if block.state_root != state_root(&accounts) {
+                return Err(reject(BlockRejection::BadStateRoot));
+            }
+            previous_hash = hash;
+            previous_proof = block.proof;
+            coinbase_height.push(block);
+            recent_timestamps.push_back(block.timestamp);
+            if recent_timestamps.len() > MEDIAN_TIME_SPAN {
+                recent_timestamps.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays every mined asset operation to find each asset's current owner
+    fn asset_registry(&self) -> AssetRegistry {
+        let mut assets = AssetRegistry::default();
+        for transaction in self.chain.iter().flat_map(|block| &block.transactions) {
+            let _ = assets.apply(transaction);
+        }
+        assets
+    }
+
+    /// Returns the current owner of a unique asset
+    fn owner_of(&self, asset_id: &str) -> Option<Address> {
+        self.asset_registry().owners.remove(asset_id)
+    }
+
+    /// Returns the ids of every asset the address owns, sorted
+    fn assets_of(&self, address: &str) -> Vec<String> {
+        let mut assets: Vec<String> = self
+            .asset_registry()
+            .owners
+            .into_iter()
+            .filter(|(_, owner)| owner == address)
+            .map(|(asset_id, _)| asset_id)
+            .collect();
+        assets.sort();
+        assets
+    }
+
+    /// Replays every mined name operation to rebuild the name registry
+    fn name_registry(&self) -> NameRegistry {
+        let mut names = NameRegistry::default();
+        for block in &self.chain {
+            for transaction in &block.transactions {
+                let _ = names.apply(transaction, block.index);
+            }
+        }
+        names
+    }
+
+    /// Returns the registration of a name that has not expired
+    fn lookup_name(&self, name: &str) -> Option<NameRecord> {
+        self.name_registry().active(name, self.last_block().index).cloned()
+    }
+
+    /// Returns the address or data a registered name points at
+    fn resolve_name(&self, name: &str) -> Option<Address> {
+        self.lookup_name(name).map(|record| record.target)
+    }
+
+    /// Replays the payments in blocks up to and including `height` to find
+    /// each address's balance and next nonce
+    ///
+    /// Replay starts from the newest snapshot at or below `height`, and a new
+    /// snapshot is taken at every `STATE_SNAPSHOT_INTERVAL`th block passed.
+    fn account_states(&self, height: u64) -> HashMap<Address, AccountState> {
+        let height = height.min(self.last_block().index);
+        let mut snapshots = self.state_snapshots.borrow_mut();
+        let mut snapshot_height = height - height % STATE_SNAPSHOT_INTERVAL;
+        let (mut accounts, start) = loop {
+            if snapshot_height == 0 {
+                break (HashMap::new(), 0);
+            }
+            if let Some(accounts) = snapshots.get(&self.chain[snapshot_height as usize].calculate_hash()) {
+                break (accounts.clone(), snapshot_height as usize + 1);
+            }
+            snapshot_height -= STATE_SNAPSHOT_INTERVAL;
+        };
+        for block in &self.chain[start..=height as usize] {
+            for transaction in &block.transactions {
+                apply_payment(&mut accounts, transaction);
+            }
+            if block.index > 0 && block.index % STATE_SNAPSHOT_INTERVAL == 0 {
+                snapshots.insert(block.calculate_hash(), accounts.clone());
+            }
+        }
+        accounts
+    }
+
+    /// Returns the address's balance once the block at `height` was applied
+    fn balance_at(&self, address: &str, height: u64) -> f64 {
+        self.account_states(height).get(address).map_or(0.0, |state| state.balance)
+    }
+
+    /// Returns the state root a block containing `transactions` on top of the
+    /// current tip must commit to
+    fn state_root_after(&self, transactions: &[Transaction]) -> String {
+        let mut accounts = self.account_states(self.last_block().index);
+        for transaction in transactions {
+            apply_payment(&mut accounts, transaction);
+        }
+        state_root(&accounts)
+    }
+
+    /// Returns each address's balance at the tip
+    fn balances(&self) -> HashMap<Address, f64> {
+        self.account_states(self.last_block().index)
+            .into_iter()
+            .map(|(address, state)| (address, state.balance))
+            .collect()
+    }
+
+    /// Lists every address whose balance or nonce differs between the states
+    /// after the two main-chain blocks, sorted by address
+    ///
+    /// Returns `None` if either hash is not on the main chain. Accounts here
+    /// have balances rather than unspent outputs, so there are no created or
+    /// spent outputs to report.
+    fn state_diff(&self, from_hash: &str, to_hash: &str) -> Option<Vec<AccountChange>> {
+        let height_of = |hash: &str| {
+            self.chain
+                .iter()
+                .find(|block| block.calculate_hash() == hash)
+                .map(|block| block.index)
+        };
+        let before = self.account_states(height_of(from_hash)?);
+        let after = self.account_states(height_of(to_hash)?);
+        let addresses: HashSet<&Address> = before.keys().chain(after.keys()).collect();
+        let mut changes: Vec<AccountChange> = addresses
+            .into_iter()
+            .map(|address| AccountChange {
+                address: address.clone(),
+                before: before.get(address).copied().unwrap_or_default(),
+                after: after.get(address).copied().unwrap_or_default(),
+            })
+            .filter(|change| change.before != change.after)
+            .collect();
+        changes.sort_by(|a, b| a.address.cmp(&b.address));
+        Some(changes)
+    }
+
+    /// Re-executes a mined transaction against the state just before it and
+    /// returns every account it touched, sender first, along with the asset
+    /// and name it wrote, or `None` if it is not on the main chain
+    ///
+    /// Transactions run no scripts, so updating these accounts and registry
+    /// entries is the whole of their execution.
+    fn trace_transaction(&self, txid: &str) -> Option<TransactionTrace> {
+        let (block, position) = self.chain.iter().find_map(|block| {
+            let position = block.transactions.iter().position(|transaction| transaction.hash() == txid)?;
+            Some((block, position))
+        })?;
+        let mut accounts = if block.index == 0 { HashMap::new() } else { self.account_states(block.index - 1) };
+        let mut assets = AssetRegistry::default();
+        let mut names = NameRegistry::default();
+        for earlier in &self.chain[..block.index as usize] {
+            for transaction in &earlier.transactions {
+                let _ = assets.apply(transaction);
+                let _ = names.apply(transaction, earlier.index);
+            }
+        }
+        for transaction in &block.transactions[..position] {
+            apply_payment(&mut accounts, transaction);
+            let _ = assets.apply(transaction);
+            let _ = names.apply(transaction, block.index);
+        }
+        let transaction = &block.transactions[position];
+        let asset_id = match &transaction.asset {
+            Some(AssetOperation::Mint { .. }) => Some(transaction.hash()),
+            Some(operation) => operation.asset_id().cloned(),
+            None => None,
+        };
+        let asset_before = asset_id.as_ref().map(|asset_id| assets.owners.get(asset_id).cloned());
+        let name = transaction.name.as_ref().map(NameOperation::name);
+        let name_before = name.map(|name| names.records.get(name).cloned());
+        let _ = assets.apply(transaction);
+        let _ = names.apply(transaction, block.index);
+        let mut touched = vec![transaction.sender.clone()];
+        for output in &transaction.outputs {
+            if !touched.contains(&output.recipient) {
+                touched.push(output.recipient.clone());
+            }
+        }
+        let before: Vec<AccountState> =
+            touched.iter().map(|address| accounts.get(address).copied().unwrap_or_default()).collect();
+        apply_payment(&mut accounts, transaction);
+        Some(TransactionTrace {
+            accounts: touched
+                .into_iter()
+                .zip(before)
+                .map(|(address, before)| AccountChange {
+                    after: accounts.get(&address).copied().unwrap_or_default(),
+                    address,
+                    before,
+                })
+                .collect(),
+            asset: asset_id.zip(asset_before).map(|(asset_id, before)| AssetChange {
+                after: assets.owners.get(&asset_id).cloned(),
+                asset_id,
+                before,
+            }),
+            name: name.zip(name_before).map(|(name, before)| NameChange {
+                after: names.records.get(name).cloned(),
+                name: name.clone(),
+                before,
+            }),
+        })
+    }
+
+    /// Returns the `n` addresses with the highest balances, richest first
+    /// (ties are broken by address)
+    fn richest_accounts(&self, n: usize) -> Vec<(Address, f64)> {
+        let mut accounts: Vec<(Address, f64)> = self.balances().into_iter().collect();
+        accounts.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        accounts.truncate(n);
+        accounts
+    }
+
+    /// Suggests a fee rate, in coins per byte, for a transaction to be mined
+    /// within `confirmation_target` blocks
+    ///
+    /// Two signals are combined, and the higher one wins. The first is the
+    /// rate needed to rank inside the first `confirmation_target` blocks' worth
+    /// of pending transactions, read from the fee histogram to the nearest
+    /// bucket. The second is the median lowest fee rate that recent blocks
+    /// included. The estimate never drops below the relay policy's minimum fee
+    /// rate.
+    fn estimate_fee(&self, confirmation_target: u64) -> f64 {
+        let capacity = confirmation_target.max(1) as usize * MAX_BLOCK_VSIZE;
+        let mempool_rate = self.fee_histogram.rate_to_fit(capacity);
+        let start = self.chain.len().saturating_sub(FEE_ESTIMATE_BLOCKS);
+        let mut block_rates: Vec<f64> = self.chain[start..]
+            .iter()
+            .filter_map(|block| {
+                block
+                    .transactions
+                    .iter()
+                    .filter(|transaction| transaction.sender != "0")
+                    .map(Transaction::fee_rate)
+                    .min_by(f64::total_cmp)
+            })
+            .collect();
+        block_rates.sort_by(f64::total_cmp);
+        let recent_rate = block_rates.get(block_rates.len() / 2).copied().unwrap_or(0.0);
+        mempool_rate.max(recent_rate).max(self.relay_policy.min_fee_rate)
+    }
+
+    /// Lists the pending transactions matching `filter`, ordered by fee rate
+    /// then txid, with a summary of all matches
+    ///
+    /// Ancestors and descendants count every pending transaction from the same
+    /// sender, whether or not the filter matched it.
+    fn get_mempool(&self, filter: &MempoolFilter, page: Page) -> MempoolView {
+        let now = self.clock.now();
+        let mut entries: Vec<MempoolEntry> = self
+            .current_transactions
+            .iter()
+            .filter(|transaction| filter.matches(transaction))
+            .map(|transaction| {
+                let siblings = self.current_transactions.iter().filter(|other| other.sender == transaction.sender);
+                let txid = transaction.hash();
+                let since = self.pending_since.get(&txid).copied().unwrap_or(now);
+                MempoolEntry {
+                    txid,
+                    transaction: transaction.clone(),
+                    vsize: transaction.vsize(),
+                    fee_rate: transaction.fee_rate(),
+                    age: (now - since).max(0),
+                    ancestors: siblings.clone().filter(|other| other.nonce < transaction.nonce).count(),
+                    descendants: siblings.clone().filter(|other| other.nonce > transaction.nonce).count(),
+                    depends_on: siblings.clone().find(|other| other.nonce + 1 == transaction.nonce).map(Transaction::hash),
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| b.fee_rate.total_cmp(&a.fee_rate).then_with(|| a.txid.cmp(&b.txid)));
+        let summary = match (entries.first(), entries.last()) {
+            (Some(highest), Some(lowest)) => MempoolSummary {
+                count: entries.len(),
+                total_vsize: entries.iter().map(|entry| entry.vsize).sum(),
+                total_fees: entries.iter().map(|entry| entry.transaction.fee).sum(),
+                min_fee_rate: lowest.fee_rate,
+                median_fee_rate: entries[entries.len() / 2].fee_rate,
+                max_fee_rate: highest.fee_rate,
+            },
+            _ => MempoolSummary::default(),
+        };
+        let entries = entries.into_iter().skip(page.number.saturating_mul(page.size)).take(page.size).collect();
+        MempoolView { entries, summary }
+    }
+
+    /// Returns the height at and below which blocks can no longer be reorganised
+    fn finalized_height(&self) -> u64 {
+        self.last_block().index.saturating_sub(FINALITY_DEPTH)
+    }
+
+    /// Returns how many blocks confirm the transaction, counting the block that
+    /// includes it, or 0 if it is pending or unknown
+    fn confirmations(&self, txid: &str) -> u64 {
+        self.chain
+            .iter()
+            .find(|block| block.transactions.iter().any(|transaction| transaction.hash() == txid))
+            .map_or(0, |block| self.last_block().index - block.index + 1)
+    }
+
+    /// Reports whether the transaction is pending, confirmed, conflicted or
+    /// dropped
+    ///
+    /// The status is derived from the current tip each time. A reorg that
+    /// disconnects a transaction therefore moves it straight back to
+    /// `Pending`, or to `Conflicted` if the new chain spent its nonce.
+    fn transaction_status(&self, transaction: &Transaction) -> TransactionStatus {
+        let txid = transaction.hash();
+        let confirmations = self.confirmations(&txid);
+        if confirmations > 0 {
+            return TransactionStatus::Confirmed { confirmations };
+        }
+        if self.current_transactions.contains(transaction) {
+            return TransactionStatus::Pending;
+        }
+        self.chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .chain(&self.current_transactions)
+            .find(|other| other.sender == transaction.sender && other.nonce == transaction.nonce)
+            .map_or(TransactionStatus::Dropped, |other| TransactionStatus::Conflicted { by: other.hash() })
+    }
+
+    /// Switches to a longer competing chain that shares our genesis block
+    ///
+    /// Forks that would roll back finalized blocks are refused before the
+    /// candidate is validated. Disconnected blocks are kept as stale blocks and
+    /// their transactions return to the pending list, unless the new chain
+    /// contains them or spends the same sender nonce.
+    fn replace_chain(&mut self, candidate: Vec<Block>) -> Result<(), ReorgError> {
+        if candidate.len() <= self.chain.len() {
+            return Err(ReorgError::NotLonger);
+        }
+        if candidate.len() - self.chain.len() > MAX_NEW_BLOCKS_PER_OFFER {
+            return Err(ReorgError::TooManyBlocks);
+        }
+        let fork_height = self
+            .chain
+            .iter()
+            .zip(&candidate)
+            .position(|(ours, theirs)| ours.calculate_hash() != theirs.calculate_hash())
+            .unwrap_or(self.chain.len()) as u64;
+        if fork_height == 0 {
+            return Err(ReorgError::WrongGenesis);
+        }
+        let finalized_height = self.finalized_height();
+        if fork_height <= finalized_height {
+            return Err(ReorgError::ForkBelowFinality { fork_height, finalized_height });
+        }
+        self.check_chain(&candidate).map_err(ReorgError::InvalidBlock)?;
+        let disconnected = self.chain.split_off(fork_height as usize);
+        self.chain = candidate;
+        let mined: HashSet<String> = self.chain[fork_height as usize..]
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(Transaction::hash)
+            .collect();
+        let spent: HashSet<(&str, u64)> = self.chain[fork_height as usize..]
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .filter(|transaction| transaction.sender != "0")
+            .map(|transaction| (transaction.sender.as_ref(), transaction.nonce))
+            .collect();
+        let now = self.clock.now();
+        for block in disconnected {
+            for transaction in &block.transactions {
+                if transaction.sender != "0" && !spent.contains(&(transaction.sender.as_ref(), transaction.nonce)) {
+                    self.pending_since.entry(transaction.hash()).or_insert(now);
+                    self.fee_histogram.add(transaction);
+                    self.current_transactions.push(transaction.clone());
+                }
+            }
+            self.stale_blocks.push(block);
+        }
+        let fee_histogram = &mut self.fee_histogram;
+        let pending_since = &mut self.pending_since;
+        self.current_transactions.retain(|transaction| {
+            let pending = !mined.contains(&transaction.hash())
+                && !spent.contains(&(transaction.sender.as_ref(), transaction.nonce));
+            if !pending {
+                pending_since.remove(&transaction.hash());
+                fee_histogram.remove(transaction);
+            }
+            pending
+        });
+        self.chain_updated();
+        Ok(())
+    }
+
+    /// Implements a simple proof-of-work algorithm
+    fn proof_of_work(&self, last_proof: u64) -> u64 {
+        let mut proof = 0;
+        while !self.valid_proof(last_proof, proof) {
+            proof += 1;
+        }
+        proof
+    }
+
+    /// Validates the proof: does hash(last_proof, proof) contain 4 leading zeroes?
+    fn valid_proof(&self, last_proof: u64, proof: u64) -> bool {
+        let guess = format!("{}{}", last_proof, proof);
+        let guess_hash = Sha256::digest(guess.as_bytes());
+        let result = format!("{:x}", guess_hash);
+        result.starts_with(DIFFICULTY_PREFIX)
+    }
+
+    /// Expected number of hashes needed to find a valid proof
+    fn block_work(&self) -> u64 {
+        16u64.pow(DIFFICULTY_PREFIX.len() as u32)
+    }
+
+    /// Renders the block tree as a Graphviz DOT or Mermaid diagram, labelling
+    /// each block with its height, hash and cumulative work. Stale blocks are
+    /// drawn dashed next to the main chain block they lost to.
+    fn graph(&self, format: GraphFormat) -> String {
+        let mut lines = Vec::new();
+        match format {
+            GraphFormat::Dot => lines.push(String::from("digraph chain {\n    rankdir=LR;\n    node [shape=box];")),
+            GraphFormat::Mermaid => lines.push(String::from("graph LR")),
+        }
+        let blocks = self.chain.iter().map(|block| (block, false));
+        let stale_blocks = self.stale_blocks.iter().map(|block| (block, true));
+        for (block, stale) in blocks.chain(stale_blocks) {
+            let cumulative_work = (block.index + 1) * self.block_work();
+            let hash = block.calculate_hash();
+            let id = format!("b{}", &hash[..16]);
+            let short_hash = &hash[..12];
+            let status = if stale { "<br/>stale" } else { "" };
+            match format {
+                GraphFormat::Dot => {
+                    let style = if stale { ", style=dashed" } else { "" };
+                    lines.push(format!(
+                        "    {} [label=\"height {}\\n{}\\nwork {}\"{}];",
+                        id, block.index, short_hash, cumulative_work, style
+                    ));
+                    if block.index > 0 {
+                        lines.push(format!("    b{} -> {};", &block.previous_hash[..16], id));
+                    }
+                }
+                GraphFormat::Mermaid => {
+                    lines.push(format!(
+                        "    {}[\"height {}<br/>{}<br/>work {}{}\"]",
+                        id, block.index, short_hash, cumulative_work, status
+                    ));
+                    if block.index > 0 {
+                        let arrow = if stale { "-.->" } else { "-->" };
+                        lines.push(format!("    b{} {} {}", &block.previous_hash[..16], arrow, id));
+                    }
+                }
+            }
+        }
+        if let GraphFormat::Dot = format {
+            lines.push(String::from("}"));
+        }
+        lines.join("\n")
+    }
+
+    /// Returns blocks with valid proofs that lost the race for their height
+    fn stale_blocks(&self) -> &[Block] {
+        &self.stale_blocks
+    }
+
+    /// Summarises the chain, including how often mined blocks went stale
+    fn stats(&self) -> ChainStats {
+        ChainStats {
+            height: self.last_block().index,
+            transactions: self.chain.iter().map(|block| block.transactions.len()).sum(),
+            size: self.chain.iter().map(Block::size).sum(),
+            stale_blocks: self.stale_blocks.len(),
+        }
+    }
+}
+
+/// Reasons a faucet request can be turned down
+#[derive(Debug, Clone, PartialEq)]
+enum FaucetError {
+    /// The address was paid recently and may ask again from `retry_height`
+    RateLimited { retry_height: u64 },
+    /// The payment was refused by the pending list
+    Rejected(MempoolError),
+}
+
+/// Hands out small amounts from a funding address so students on a shared
+/// test network can get coins without mining
+struct Faucet {
+    funding_address: Address,
+    amount: f64,
+    /// Blocks an address must wait between payments
+    cooldown_blocks: u64,
+    /// Height at which each address was last paid
+    last_paid: HashMap<Address, u64>,
+}
+
+impl Faucet {
+    /// Creates a faucet paying `amount` from `funding_address`
+    fn new(funding_address: Address, amount: f64, cooldown_blocks: u64) -> Self {
+        Faucet {
+            funding_address,
+            amount,
+            cooldown_blocks,
+            last_paid: HashMap::new(),
+        }
+    }
+
+    /// Queues a payment to `address` unless it was paid within the cooldown
+    fn request(&mut self, blockchain: &mut Blockchain, address: &str) -> Result<TxId, FaucetError> {
+        let height = blockchain.last_block().index;
+        if let Some(&paid_at) = self.last_paid.get(address) {
+            let retry_height = paid_at + self.cooldown_blocks;
+            if height < retry_height {
+                return Err(FaucetError::RateLimited { retry_height });
+            }
+        }
+        let mut transaction = Transaction {
+            sender: self.funding_address.clone(),
+            outputs: vec![Output::new(Address::from(address), self.amount)],
+            lock_time: 0,
+            nonce: blockchain.next_nonce(&self.funding_address),
+            fee: 0.0,
+            asset: None,
+            name: None,
+            stamp: 0,
+        };
+        transaction.fee = blockchain.min_fee(&transaction);
+        let txid = transaction.hash();
+        blockchain.submit_transaction(transaction).map_err(FaucetError::Rejected)?;
+        self.last_paid.insert(Address::from(address), height);
+        Ok(txid)
+    }
+}
+
+/// A request to be paid, shared as a `chain:<address>?amount=..&memo=..` URI
+#[derive(Debug, Clone, PartialEq)]
+struct PaymentRequest {
+    address: Address,
+    amount: Option<Amount>,
+    memo: Option<String>,
+}
+
+/// Reasons a payment request URI cannot be read
+#[derive(Debug, Clone, PartialEq)]
+enum PaymentRequestError {
+    /// The URI does not start with `chain:`
+    WrongScheme,
+    /// No address follows the scheme
+    MissingAddress,
+    /// The amount cannot be read
+    InvalidAmount(AmountError),
+    /// The amount is zero or negative
+    NotPositive,
+    /// A percent escape is malformed or decodes to invalid UTF-8
+    InvalidEncoding,
+}
+
+impl PaymentRequest {
+    /// Reads a payment request URI; unknown parameters are ignored
+    fn parse(uri: &str) -> Result<Self, PaymentRequestError> {
+        let rest = uri
+            .strip_prefix(PAYMENT_URI_SCHEME)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .ok_or(PaymentRequestError::WrongScheme)?;
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let address = percent_decode(address)?;
+        if address.is_empty() {
+            return Err(PaymentRequestError::MissingAddress);
+        }
+        let mut request = PaymentRequest { address: Address::from(address.as_str()), amount: None, memo: None };
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            match key {
+                "amount" => {
+                    let amount = Amount::parse(value).map_err(PaymentRequestError::InvalidAmount)?;
+                    if amount <= Amount(0) {
+                        return Err(PaymentRequestError::NotPositive);
+                    }
+                    request.amount = Some(amount);
+                }
+                "memo" => request.memo = Some(percent_decode(value)?),
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+
+    /// Formats the request as a URI that `parse` reads back unchanged, as
+    /// long as any amount is positive
+    fn to_uri(&self) -> String {
+        let mut parameters = Vec::new();
+        if let Some(amount) = self.amount {
+            parameters.push(format!("amount={:#}", amount));
+        }
+        if let Some(memo) = &self.memo {
+            parameters.push(format!("memo={}", percent_encode(memo)));
+        }
+        let mut uri = format!("{}:{}", PAYMENT_URI_SCHEME, percent_encode(&self.address));
+        if !parameters.is_empty() {
+            uri.push('?');
+            uri.push_str(&parameters.join("&"));
+        }
+        uri
+    }
+
+    /// Pre-fills a transaction paying this request from `sender`, or `None`
+    /// when the request leaves the amount to the payer
+    fn to_transaction_request(&self, sender: Address, fee: f64) -> Option<TransactionRequest> {
+        let amount = self.amount?;
+        Some(TransactionRequest {
+            sender,
+            outputs: vec![Output::new(self.address.clone(), amount.to_coins())],
+            fee,
+            lock_time: 0,
+            nonce: None,
+        })
+    }
+}
+
+/// Escapes every byte outside the URI unreserved set as `%XX`
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Reverses `percent_encode`, also reading `+` as a space
+fn percent_decode(value: &str) -> Result<String, PaymentRequestError> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).ok_or(PaymentRequestError::InvalidEncoding)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| PaymentRequestError::InvalidEncoding)?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| PaymentRequestError::InvalidEncoding)?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| PaymentRequestError::InvalidEncoding)
+}
+
+/// Chain analysis over a range of heights, for teaching how payments can be
+/// traced between addresses
+mod analytics {
+    use super::*;
+    use chrono::TimeZone;
+    use std::collections::BTreeMap;
+    use std::ops::RangeInclusive;
+
+    /// What one address did within the analysed heights
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct AddressActivity {
+        pub sent: usize,
+        pub received: usize,
+        pub amount_sent: f64,
+        pub amount_received: f64,
+        pub first_seen: u64,
+        pub last_seen: u64,
+    }
+
+    /// Total value moved from one address to another
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Flow {
+        pub from: Address,
+        pub to: Address,
+        pub amount: f64,
+        /// Outputs that contributed to the flow
+        pub payments: usize,
+    }
+
+    /// One mined transaction as seen by a single address
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct HistoryEntry {
+        pub height: u64,
+        pub timestamp: i64,
+        pub txid: TxId,
+        /// Net change to the address's balance, excluding the fee
+        pub amount: Amount,
+        /// Fee paid by the address, zero for incoming payments
+        pub fee: Amount,
+        /// The sender of an incoming payment, or the other recipients of an
+        /// outgoing one
+        pub counterparties: Vec<Address>,
+        /// Balance once this transaction has been applied
+        pub balance: Amount,
+    }
+
+    /// Output formats supported by the history export
+    #[derive(Debug, Clone, Copy)]
+    pub enum HistoryFormat {
+        Csv,
+        Json,
+    }
+
+    /// Main-chain blocks whose height falls within `heights`
+    fn blocks(blockchain: &Blockchain, heights: RangeInclusive<u64>) -> impl Iterator<Item = &Block> {
+        blockchain.chain.iter().filter(move |block| heights.contains(&block.index))
+    }
+
+    /// Returns the activity entry for `address`, creating it at `height`
+    fn seen<'a>(
+        activity: &'a mut BTreeMap<Address, AddressActivity>,
+        address: &Address,
+        height: u64,
+    ) -> &'a mut AddressActivity {
+        let entry = activity.entry(address.clone()).or_insert(AddressActivity {
+            sent: 0,
+            received: 0,
+            amount_sent: 0.0,
+            amount_received: 0.0,
+            first_seen: height,
+            last_seen: height,
+        });
+        entry.last_seen = height;
+        entry
+    }
+
+    /// Returns send and receive statistics for every address active within `heights`
+    pub fn activity(blockchain: &Blockchain, heights: RangeInclusive<u64>) -> BTreeMap<Address, AddressActivity> {
+        let mut activity = BTreeMap::new();
+        for block in blocks(blockchain, heights) {
+            for transaction in &block.transactions {
+                let sender = seen(&mut activity, &transaction.sender, block.index);
+                sender.sent += 1;
+                sender.amount_sent += transaction.outputs.iter().map(|output| output.amount).sum::<f64>();
+                for output in &transaction.outputs {
+                    let recipient = seen(&mut activity, &output.recipient, block.index);
+                    recipient.received += 1;
+                    recipient.amount_received += output.amount;
+                }
+            }
+        }
+        activity
+    }
+
+    /// Groups the senders within `heights` that appear to be controlled by the
+    /// same person
+    ///
+    /// Addresses that fund the same transaction are merged, the naive
+    /// common-input heuristic. Transactions here have a single sender, so on
+    /// its own that only ever yields one address per cluster. A name's
+    /// registrant is therefore also merged with the address the name resolves
+    /// to. Each cluster is sorted, and clusters are ordered by their first
+    /// address.
+    pub fn clusters(blockchain: &Blockchain, heights: RangeInclusive<u64>) -> Vec<Vec<Address>> {
+        let mut parent: BTreeMap<Address, Address> = BTreeMap::new();
+        fn root(parent: &mut BTreeMap<Address, Address>, address: &Address) -> Address {
+            let next = parent.entry(address.clone()).or_insert_with(|| address.clone()).clone();
+            if next == *address {
+                return next;
+            }
+            let root = root(parent, &next);
+            parent.insert(address.clone(), root.clone());
+            root
+        }
+        for block in blocks(blockchain, heights) {
+            for transaction in &block.transactions {
+                let sender = root(&mut parent, &transaction.sender);
+                if let Some(NameOperation::Register { target, .. }) = &transaction.name {
+                    let target = root(&mut parent, target);
+                    if sender != target {
+                        parent.insert(sender.clone().max(target.clone()), sender.min(target));
+                    }
+                }
+            }
+        }
+        let addresses: Vec<Address> = parent.keys().cloned().collect();
+        let mut clusters: BTreeMap<Address, Vec<Address>> = BTreeMap::new();
+        for address in addresses {
+            clusters.entry(root(&mut parent, &address)).or_default().push(address);
+        }
+        clusters.into_values().collect()
+    }
+
+    /// Sums every payment within `heights` by sender and recipient
+    pub fn value_flows(blockchain: &Blockchain, heights: RangeInclusive<u64>) -> Vec<Flow> {
+        let mut flows: BTreeMap<(Address, Address), Flow> = BTreeMap::new();
+        for block in blocks(blockchain, heights) {
+            for transaction in &block.transactions {
+                for output in &transaction.outputs {
+                    let key = (transaction.sender.clone(), output.recipient.clone());
+                    let flow = flows.entry(key).or_insert_with(|| Flow {
+                        from: transaction.sender.clone(),
+                        to: output.recipient.clone(),
+                        amount: 0.0,
+                        payments: 0,
+                    });
+                    flow.amount += output.amount;
+                    flow.payments += 1;
+                }
+            }
+        }
+        flows.into_values().collect()
+    }
+
+    /// Lists every main-chain transaction that sent from or paid `address`,
+    /// oldest first, with the balance after each one
+    ///
+    /// Each output and fee is rounded to the nearest smallest unit before it
+    /// is added up, so the running balance does not drift.
+    pub fn history(blockchain: &Blockchain, address: &str) -> Vec<HistoryEntry> {
+        let units = |coins: f64| Amount::nearest(coins).0;
+        let mut entries = Vec::new();
+        let mut balance = 0;
+        for block in &blockchain.chain {
+            for transaction in &block.transactions {
+                let sent = transaction.sender == address && transaction.sender != "0";
+                let received: i64 = transaction
+                    .outputs
+                    .iter()
+                    .filter(|output| output.recipient == address)
+                    .map(|output| units(output.amount))
+                    .sum();
+                if !sent && !transaction.outputs.iter().any(|output| output.recipient == address) {
+                    continue;
+                }
+                let (amount, fee, counterparties) = if sent {
+                    let spent: i64 = transaction.outputs.iter().map(|output| units(output.amount)).sum();
+                    let mut recipients: Vec<Address> = transaction
+                        .outputs
+                        .iter()
+                        .map(|output| output.recipient.clone())
+                        .filter(|recipient| recipient != address)
+                        .collect();
+                    recipients.sort();
+                    recipients.dedup();
+                    (received - spent, units(transaction.fee), recipients)
+                } else {
+                    (received, 0, vec![transaction.sender.clone()])
+                };
+                balance += amount - fee;
+                entries.push(HistoryEntry {
+                    height: block.index,
+                    timestamp: block.timestamp,
+                    txid: transaction.hash(),
+                    amount: Amount(amount),
+                    fee: Amount(fee),
+                    counterparties,
+                    balance: Amount(balance),
+                });
+            }
+        }
+        entries
+    }
+
+    /// Renders a history in the requested format
+    pub fn export_history(entries: &[HistoryEntry], format: HistoryFormat) -> String {
+        match format {
+            HistoryFormat::Csv => history_to_csv(entries),
+            HistoryFormat::Json => history_to_json(entries),
+        }
+    }
+
+    /// Renders a history as CSV with a header row, one line per entry
+    ///
+    /// Amounts are plain decimals without thousands separators. Counterparties
+    /// share a single column, separated by semicolons.
+    pub fn history_to_csv(entries: &[HistoryEntry]) -> String {
+        let mut lines = vec![String::from("date,height,txid,amount,fee,counterparties,balance")];
+        for entry in entries {
+            lines.push(format!(
+                "{},{},{},{:#},{:#},{},{:#}",
+                date(entry.timestamp),
+                entry.height,
+                entry.txid,
+                entry.amount,
+                entry.fee,
+                csv_field(&entry.counterparties.join(";")),
+                entry.balance
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Renders a history as a JSON array with one object per entry, amounts
+    /// as JSON numbers
+    pub fn history_to_json(entries: &[HistoryEntry]) -> String {
+        let objects: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let counterparties: Vec<String> =
+                    entry.counterparties.iter().map(|address| json_string(address)).collect();
+                format!(
+                    "{{\"date\":{},\"height\":{},\"txid\":{},\"amount\":{:#},\"fee\":{:#},\"counterparties\":[{}],\"balance\":{:#}}}",
+                    json_string(&date(entry.timestamp)),
+                    entry.height,
+                    json_string(&entry.txid),
+                    entry.amount,
+                    entry.fee,
+                    counterparties.join(","),
+                    entry.balance
+                )
+            })
+            .collect();
+        format!("[{}]", objects.join(","))
+    }
+
+    /// Formats a Unix timestamp as an RFC 3339 UTC date
+    fn date(timestamp: i64) -> String {
+        Utc.timestamp_opt(timestamp, 0)
+            .single()
+            .map_or_else(|| timestamp.to_string(), |date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    }
+
+    /// Quotes a CSV field when it contains a comma, quote or line break
+    fn csv_field(value: &str) -> String {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Renders flows as a Graphviz digraph with one labelled edge per flow
+    pub fn flows_to_dot(flows: &[Flow]) -> String {
+        let mut lines = vec![String::from("digraph flows {\n    rankdir=LR;")];
+        for flow in flows {
+            lines.push(format!(
+                "    {:?} -> {:?} [label=\"{} ({})\"];",
+                flow.from, flow.to, flow.amount, flow.payments
+            ));
+        }
+        lines.push(String::from("}"));
+        lines.join("\n")
+    }
+
+    /// Renders flows as a JSON array of `{from, to, amount, payments}` objects
+    pub fn flows_to_json(flows: &[Flow]) -> String {
+        let objects: Vec<String> = flows
+            .iter()
+            .map(|flow| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"amount\":{},\"payments\":{}}}",
+                    json_string(&flow.from),
+                    json_string(&flow.to),
+                    flow.amount,
+                    flow.payments
+                )
+            })
+            .collect();
+        format!("[{}]", objects.join(","))
+    }
+
+    /// Quotes `value` as a JSON string
+    fn json_string(value: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in value.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+}
+
+/// Mining races for hands-on attack labs
+///
+/// Each block is found by the attacker or pool with probability equal to its
+/// hash power share, so thousands of trials run without doing any proof of work.
+mod simulation {
+    use super::FINALITY_DEPTH;
+
+    /// An attacker pays a merchant, then mines a private fork that leaves the
+    /// payment out and publishes it once it is longer than the honest chain
+    #[derive(Debug, Clone)]
+    pub struct DoubleSpendScenario {
+        /// Fraction of total hash power held by the attacker, at least 0 and
+        /// below 1
+        pub attacker_share: f64,
+        /// Confirmations the merchant waits for before handing over the goods
+        pub confirmations: u64,
+        /// Blocks the attacker may fall behind before giving up
+        pub max_deficit: u64,
+        pub trials: u64,
+        pub seed: u64,
+    }
+
+    /// Outcome of running a scenario many times
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct DoubleSpendReport {
+        pub trials: u64,
+        pub successes: u64,
+        pub success_rate: f64,
+    }
+
+    impl DoubleSpendScenario {
+        /// Runs every trial and reports how often the double spend succeeded,
+        /// or `None` if `attacker_share` is outside `[0, 1)`
+        ///
+        /// The merchant's node follows `Blockchain::replace_chain`, so it
+        /// switches to any longer chain unless that would undo more than
+        /// `FINALITY_DEPTH` blocks. An attacker with all the hash power would
+        /// leave the honest chain stalled and the race without an end.
+        pub fn run(&self) -> Option<DoubleSpendReport> {
+            if !(0.0..1.0).contains(&self.attacker_share) {
+                return None;
+            }
+            let mut rng = Rng::new(self.seed);
+            let successes = (0..self.trials).filter(|_| self.trial(&mut rng)).count() as u64;
+            Some(DoubleSpendReport {
+                trials: self.trials,
+                successes,
+                success_rate: successes as f64 / self.trials.max(1) as f64,
+            })
+        }
+
+        /// Races the private fork against the honest chain from the block
+        /// before the payment was mined
+        fn trial(&self, rng: &mut Rng) -> bool {
+            let (mut honest, mut attacker) = (0u64, 0u64);
+            loop {
+                if rng.next_f64() < self.attacker_share {
+                    attacker += 1;
+                } else {
+                    honest += 1;
+                }
+                if honest > FINALITY_DEPTH || honest > attacker + self.max_deficit {
+                    return false;
+                }
+                if honest >= self.confirmations && attacker > honest {
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// How a mining pool handles the blocks it finds
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum MinerStrategy {
+        /// Publishes every block at once
+        Honest,
+        /// Withholds blocks to build a private lead, revealing them only to
+        /// orphan honest blocks
+        Selfish,
+    }
+
+    /// A pool mining against everyone else
+    #[derive(Debug, Clone)]
+    pub struct MiningScenario {
+        /// Fraction of total hash power held by the pool
+        pub pool_share: f64,
+        /// Fraction of honest miners that build on the pool's block when two
+        /// blocks race at the same height
+        pub tie_share: f64,
+        pub strategy: MinerStrategy,
+        /// Blocks found in total, by anyone, during the run
+        pub blocks: u64,
+        pub seed: u64,
+    }
+
+    /// Blocks each side got into the final chain
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct MiningReport {
+        pub pool_blocks: u64,
+        pub honest_blocks: u64,
+        /// Blocks found but left out of the final chain
+        pub orphaned_blocks: u64,
+        /// The pool's share of blocks in the final chain, to compare with `pool_share`
+        pub revenue_share: f64,
+    }
+
+    impl MiningScenario {
+        /// Runs the scenario, following Eyal and Sirer's selfish mining strategy
+        /// when the pool is selfish
+        pub fn run(&self) -> MiningReport {
+            let mut rng = Rng::new(self.seed);
+            let (mut pool, mut honest) = (0u64, 0u64);
+            // Blocks the pool has found but not published
+            let mut lead = 0u64;
+            // Whether a published pool block and an honest block are racing at the tip
+            let mut racing = false;
+            for _ in 0..self.blocks {
+                let pool_found = rng.next_f64() < self.pool_share;
+                if self.strategy == MinerStrategy::Honest {
+                    if pool_found {
+                        pool += 1;
+                    } else {
+                        honest += 1;
+                    }
+                    continue;
+                }
+                match (pool_found, lead, racing) {
+                    (true, _, true) => {
+                        pool += 2;
+                        racing = false;
+                    }
+                    (false, _, true) => {
+                        if rng.next_f64() < self.tie_share {
+                            pool += 1;
+                            honest += 1;
+                        } else {
+                            honest += 2;
+                        }
+                        racing = false;
+                    }
+                    (true, _, false) => lead += 1,
+                    (false, 0, false) => honest += 1,
+                    (false, 1, false) => {
+                        lead = 0;
+                        racing = true;
+                    }
+                    (false, 2, false) => {
+                        pool += 2;
+                        lead = 0;
+                    }
+                    (false, _, false) => {
+                        pool += 1;
+                        lead -= 1;
+                    }
+                }
+            }
+            // Anything still withheld at the end is published
+            pool += lead;
+            if racing {
+                honest += 1;
+            }
+            let included = pool + honest;
+            MiningReport {
+                pool_blocks: pool,
+                honest_blocks: honest,
+                orphaned_blocks: self.blocks - included,
+                revenue_share: pool as f64 / included.max(1) as f64,
+            }
+        }
+    }
+
+    /// SplitMix64, a small seeded generator so simulations and fixtures need
+    /// no extra crates
+    pub struct Rng(u64);
+
+    impl Rng {
+        pub fn new(seed: u64) -> Self {
+            Rng(seed)
+        }
+
+        pub fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        /// Returns a value in `0.0..1.0`
+        pub fn next_f64(&mut self) -> f64 {
+            (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+}
+
+/// Deterministic fixtures for tests
+#[cfg(test)]
+mod testutil {
+    use super::simulation::Rng;
+    use super::*;
+    use std::cell::Cell;
+
+    /// Addresses that generated transactions are drawn from
+    const ADDRESSES: [&str; 8] = ["Alice", "Bob", "Charlie", "David", "Eve", "Frank", "Grace", "Heidi"];
+
+    /// A clock that starts at a fixed time and moves forward by `step` seconds
+    /// every time it is read
+    pub struct SteppingClock {
+        time: Cell<i64>,
+        step: i64,
+    }
+
+    impl SteppingClock {
+        pub fn new(start: i64, step: i64) -> Self {
+            SteppingClock { time: Cell::new(start), step }
+        }
+    }
+
+    impl Clock for SteppingClock {
+        fn now(&self) -> i64 {
+            let time = self.time.get();
+            self.time.set(time + self.step);
+            time
+        }
+    }
+
+    impl Rng {
+        /// Returns a value in `0..bound`
+        pub fn below(&mut self, bound: u64) -> u64 {
+            self.next_u64() % bound
+        }
+    }
+
+    /// Shape of a generated chain
+    #[derive(Debug, Clone)]
+    pub struct ChainSpec {
+        pub seed: u64,
+        /// Blocks to mine on top of the funding blocks
+        pub blocks: usize,
+        /// Transactions offered before each block; anything over
+        /// `MAX_BLOCK_VSIZE` spills into later blocks, and offers that would
+        /// break the relay policy's per-sender limits or spend more than the
+        /// sender's confirmed balance are skipped
+        pub transactions_per_block: usize,
+        /// Also mine a competing block that goes stale at every `n`th height
+        pub fork_every: Option<usize>,
+        /// Append a final block whose proof does not validate
+        pub invalid_tip: bool,
+    }
+
+    impl Default for ChainSpec {
+        fn default() -> Self {
+            ChainSpec {
+                seed: 0,
+                blocks: 5,
+                transactions_per_block: 3,
+                fork_every: None,
+                invalid_tip: false,
+            }
+        }
+    }
+
+    /// Mines a chain described by `spec`; the same spec always yields the same
+    /// blocks, hashes and stale blocks
+    ///
+    /// Every address first mines one empty block, whose reward it spends
+    /// from then on.
+    pub fn generate_chain(spec: &ChainSpec) -> Blockchain {
+        let mut rng = Rng::new(spec.seed);
+        let mut blockchain = Blockchain::with_clock(Box::new(SteppingClock::new(1_700_000_000, 60)));
+        for address in ADDRESSES {
+            blockchain.new_block(address, blockchain.proof_of_work(blockchain.last_block().proof));
+        }
+        for height in 1..=spec.blocks {
+            for _ in 0..spec.transactions_per_block {
+                let sender = ADDRESSES[rng.below(ADDRESSES.len() as u64) as usize];
+                let recipient = ADDRESSES[rng.below(ADDRESSES.len() as u64) as usize];
+                let mut transaction = Transaction {
+                    sender: Address::from(sender),
+                    outputs: vec![Output::new(Address::from(recipient), (1 + rng.below(10)) as f64 / 1000.0)],
+                    lock_time: 0,
+                    nonce: blockchain.next_nonce(sender),
+                    fee: rng.below(10) as f64 / 10_000.0,
+                    asset: None,
+                    name: None,
+                    stamp: 0,
+                };
+                transaction.fee += blockchain.relay_policy.min_fee(&transaction);
+                match blockchain.submit_transaction(transaction) {
+                    Ok(_)
+                    | Err(
+                        MempoolError::TooManyPending
+                        | MempoolError::PendingValueTooHigh
+                        | MempoolError::InsufficientFunds,
+                    ) => {}
+                    Err(error) => panic!("generated transaction rejected: {:?}", error),
+                }
+            }
+            let miner = ADDRESSES[rng.below(ADDRESSES.len() as u64) as usize];
+            let template = blockchain.build_block_template(miner);
+            let rival = spec
+                .fork_every
+                .filter(|&every| every > 0 && height % every == 0)
+                .map(|_| blockchain.build_block_template(ADDRESSES[rng.below(ADDRESSES.len() as u64) as usize]));
+            let proof = blockchain.proof_of_work(template.last_proof);
+            blockchain.submit_block(template, proof).expect("template was built on the tip");
+            if let Some(rival) = rival {
+                let _ = blockchain.submit_block(rival, proof);
+            }
+        }
+        if spec.invalid_tip {
+            let template = blockchain.build_block_template(ADDRESSES[0]);
+            let bad_proof = (0..).find(|&proof| !blockchain.valid_proof(template.last_proof, proof)).unwrap();
+            blockchain.chain.push(template.into_block(bad_proof));
+        }
+        blockchain
+    }
+}
+
+fn main() {
+    // `chain graph [dot|mermaid]` prints only the block graph once mining finishes,
+    // and `wallet export-history <address> [--format csv|json]` only that address's history
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let graph_format = match args.as_slice() {
+        ["chain", "graph"] | ["chain", "graph", "dot"] => Some(GraphFormat::Dot),
+        ["chain", "graph", "mermaid"] => Some(GraphFormat::Mermaid),
+        _ => None,
+    };
+    let history_export = match args.as_slice() {
+        ["wallet", "export-history", address] | ["wallet", "export-history", address, "--format", "csv"] => {
+            Some((Address::from(*address), analytics::HistoryFormat::Csv))
+        }
+        ["wallet", "export-history", address, "--format", "json"] => {
+            Some((Address::from(*address), analytics::HistoryFormat::Json))
+        }
+        _ => None,
+    };
+    let verbose = graph_format.is_none() && history_export.is_none();
+
+    // Create a new blockchain
+    let mut blockchain = Blockchain::new();
+
+    // Alice and Bob mine the first two blocks and spend from the rewards
+    for (ordinal, miner) in [("first", "Alice"), ("second", "Bob")] {
+        if verbose {
+            println!("Mining {} block...", ordinal);
+        }
+        let block = blockchain.new_block(miner, blockchain.proof_of_work(blockchain.last_block().proof));
+        if verbose {
+            println!("New block forged: {:?}", block);
+        }
+    }
+
+    // Alice mints a unique asset, identified by the minting transaction's hash
+    let mint = Transaction {
+        sender: Address::from("Alice"),
+        outputs: Vec::new(),
+        lock_time: 0,
+        nonce: blockchain.next_nonce("Alice"),
+        fee: 0.001,
+        asset: Some(AssetOperation::Mint {
+            metadata_hash: format!("{:x}", Sha256::digest(b"Alice's first artwork")),
+        }),
+        name: None,
+        stamp: 0,
+    };
+    let artwork = mint.hash();
+    blockchain.submit_transaction(mint).expect("mint is well formed");
+
+    // Bob claims a name that resolves to his address
+    let registration = Transaction {
+        sender: Address::from("Bob"),
+        outputs: Vec::new(),
+        lock_time: 0,
+        nonce: blockchain.next_nonce("Bob"),
+        fee: 0.001,
+        asset: None,
+        name: Some(NameOperation::Register { name: String::from("bob"), target: Address::from("Bob") }),
+        stamp: 0,
+    };
+    blockchain.submit_transaction(registration).expect("name is free");
+
+    // Alice pays rent automatically every three blocks
+    let rent = blockchain
+        .schedule_payment(Address::from("Alice"), vec![Output::new(Address::from("Landlord"), 0.05)], 0.001, 3)
+        .expect("rent payment is well formed");
+
+    // If David stops transacting for five blocks, his savings pass to Erin
+    blockchain
+        .schedule_inheritance(Address::from("David"), vec![Output::new(Address::from("Erin"), 0.05)], 0.001, 5)
+        .expect("inheritance payment is well formed");
+
+    // Each round, the named miner mines a block containing the listed
+    // transactions; every payment is funded by one received in an earlier round
+    let rounds = [
+        ("third", "Uma", vec![("Alice", "Bob", 0.5), ("Alice", "Charlie", 0.3)]),
+        ("fourth", "Mallory", vec![("Bob", "David", 0.5), ("Charlie", "Eve", 0.29)]),
+        ("fifth", "Zed", vec![("David", "Frank", 0.4), ("Eve", "Grace", 0.28)]),
+        ("sixth", "Faucet", vec![("Frank", "Henry", 0.39), ("Grace", "Ivy", 0.27)]),
+        ("seventh", "Sam", vec![("Henry", "Jack", 0.38), ("Ivy", "Kelly", 0.26)]),
+        ("eighth", "Sam", vec![("Jack", "Liam", 0.37), ("Kelly", "Mia", 0.25)]),
+        ("ninth", "Sam", vec![("Liam", "Noah", 0.36), ("Mia", "Olivia", 0.24)]),
+        ("tenth", "Sam", vec![("Noah", "Peter", 0.35), ("Olivia", "Quinn", 0.23)]),
+        ("eleventh", "Sam", vec![("Peter", "Rachel", 0.34), ("Quinn", "Sam", 0.22)]),
+    ];
+    for (ordinal, miner, transactions) in rounds {
+        if verbose {
+            println!("Mining {} block...", ordinal);
+        }
+        let last_proof = blockchain.last_block().proof;
+        let proof = blockchain.proof_of_work(last_proof);
+        for (sender, recipient, amount) in transactions {
+            if let Err(error) = blockchain.new_transaction(Address::from(sender), Address::from(recipient), amount) {
+                if verbose {
+                    println!("Transaction rejected: {:?}", error);
+                }
+            }
+        }
+        let block = blockchain.new_block(miner, proof);
+        if verbose {
+            println!("New block forged: {:?}", block);
+        }
+    }
+
+    // Alice gives her artwork to Bob; the transfer is mined in the next block
+    let transfer = Transaction {
+        sender: Address::from("Alice"),
+        outputs: Vec::new(),
+        lock_time: 0,
+        nonce: blockchain.next_nonce("Alice"),
+        fee: 0.001,
+        asset: Some(AssetOperation::Transfer { asset_id: artwork.clone(), recipient: Address::from("Bob") }),
+        name: None,
+        stamp: 0,
+    };
+    blockchain.submit_transaction(transfer).expect("Alice owns the artwork");
+
+    // Mine one more block from a template, keeping the nonce search separate.
+    // A rival miner finds a proof for the same height a moment too late.
+    blockchain
+        .new_transaction(Address::from("Rachel"), Address::from("Tina"), 0.1)
+        .expect("Rachel was paid in the eleventh block");
+    let template = blockchain.build_block_template("Sam");
+    let rival_template = blockchain.build_block_template("Tina");
+    if verbose {
+        println!(
+            "Mining from template (target {}, merkle root {})...",
+            template.target, template.merkle_root
+        );
+        let coinbase_proof = template.merkle_tree.proof(0).expect("the coinbase is the first leaf");
+        println!(
+            "Coinbase inclusion proof valid: {}",
+            verify_merkle_proof(&template.coinbase.hash(), &coinbase_proof, &template.merkle_root)
+        );
+    }
+    let proof = blockchain.proof_of_work(template.last_proof);
+    for template in [template, rival_template] {
+        match blockchain.submit_block(template, proof) {
+            Ok(block) if verbose => println!("New block forged: {:?}", block),
+            Ok(_) => {}
+            Err(error) if verbose => println!("Template rejected ({}): {:?}", error.code(), error),
+            Err(_) => {}
+        }
+    }
+
+    blockchain.cancel_scheduled_payment(rent);
+
+    if let Some(format) = graph_format {
+        println!("{}", blockchain.graph(format));
+        return;
+    }
+    if let Some((address, format)) = history_export {
+        println!("{}", analytics::export_history(&analytics::history(&blockchain, &address), format));
+        return;
+    }
+
+    // Queue a payment that only becomes minable an hour from now
+    let lock_time = blockchain.clock.now() + 60 * 60;
+    blockchain
+        .new_timelocked_transaction(Address::from("Sam"), Address::from("Tina"), 0.1, lock_time)
+        .expect("lock times are only checked when mining");
+    println!("Pending time-locked transactions: {:?}", blockchain.current_transactions);
+
+    // Bump the fee on a stuck payment by replacing it with a conflicting one
+    let payment = Transaction {
+        sender: Address::from("Tina"),
+        outputs: vec![Output::new(Address::from("Uma"), 0.05)],
+        lock_time: 0,
+        nonce: blockchain.next_nonce("Tina"),
+        fee: 0.001,
+        asset: None,
+        name: None,
+        stamp: 0,
+    };
+    let bumped = Transaction { fee: 0.005, ..payment.clone() };
+    for transaction in [payment.clone(), bumped.clone()] {
+        if let Err(error) = blockchain.submit_transaction(transaction) {
+            println!("Transaction rejected: {:?}", error);
+        }
+    }
+    println!("Original payment status: {:?}", blockchain.transaction_status(&payment));
+    println!("Bumped payment status: {:?}", blockchain.transaction_status(&bumped));
+
+    // A wallet pre-flights raw transactions without touching the pending list
+    let cheap = Transaction { fee: 0.0, nonce: bumped.nonce + 1, ..bumped.clone() };
+    let mut truncated = bumped.serialize();
+    truncated.pop();
+    for raw in [cheap.serialize(), truncated] {
+        match blockchain.validate_transaction(&raw) {
+            Ok(txid) => println!("Dry run accepted {}", txid),
+            Err(error) => println!("Dry run rejected ({}): {:?}", error.code(), error),
+        }
+    }
+    println!("Mempool events: {:?}", blockchain.take_events());
+    println!("Mempool fee histogram: {:?}", blockchain.fee_histogram.counts());
+
+    // Pay several people with one transaction
+    let payout = blockchain.new_payout_transaction(
+        Address::from("Rachel"),
+        vec![Output::new(Address::from("Uma"), 0.02), Output::new(Address::from("Victor"), 0.03)],
+    );
+    println!("Payout: {:?}", payout);
+
+    // Submit several payments at once; a bad item rejects the whole batch
+    let request = |recipient: &str, amount: f64| TransactionRequest {
+        sender: Address::from("Uma"),
+        outputs: vec![Output::new(Address::from(recipient), amount)],
+        fee: 0.001,
+        lock_time: 0,
+        nonce: None,
+    };
+    println!("Batch results: {:?}", blockchain.new_transactions(vec![request("Victor", 0.01), request("Wendy", 0.02)]));
+    println!("Batch results: {:?}", blockchain.new_transactions(vec![request("Xavier", 0.01), request("", 0.02)]));
+
+    // Inspect Uma's queued payments, which must be mined in nonce order
+    let filter = MempoolFilter { sender: Some(Address::from("Uma")), ..MempoolFilter::default() };
+    let mempool = blockchain.get_mempool(&filter, Page { number: 0, size: 10 });
+    println!("Mempool summary for Uma: {:?}", mempool.summary);
+    for entry in &mempool.entries {
+        println!(
+            "  {} at {:e} per byte, waiting {}s, {} ancestors, {} descendants, after {:?}",
+            entry.txid, entry.fee_rate, entry.age, entry.ancestors, entry.descendants, entry.depends_on
+        );
+    }
+
+    // Zero-fee spam and dust outputs are turned away
+    let spam = Transaction {
+        sender: Address::from("Mallory"),
+        outputs: vec![Output::new(Address::from("Victor"), 0.0001)],
+        lock_time: 0,
+        nonce: blockchain.next_nonce("Mallory"),
+        fee: 0.0,
+        asset: None,
+        name: None,
+        stamp: 0,
+    };
+    println!("Spam rejected: {:?}", blockchain.submit_transaction(spam.clone()));
+    println!("Dust rejected: {:?}", blockchain.submit_transaction(Transaction { fee: 0.001, ..spam.clone() }));
+
+    // One sender cannot flood the pending list
+    let flood = (0..30)
+        .map(|_| {
+            blockchain.submit_transaction(Transaction {
+                sender: Address::from("Zed"),
+                outputs: vec![Output::new(Address::from("Victor"), 0.01)],
+                lock_time: 0,
+                nonce: blockchain.next_nonce("Zed"),
+                fee: 0.001,
+                asset: None,
+                name: None,
+                stamp: 0,
+            })
+        })
+        .find_map(Result::err);
+    println!("Flood stopped: {:?}", flood);
+
+    // On a fee-less network a little work stands in for the fee
+    blockchain.relay_policy.stamp_difficulty = Some(3);
+    let mut stamped = Transaction { stamp: 0, ..spam };
+    stamped.outputs[0].amount = 0.01;
+    stamped.mint_stamp(3);
+    println!("Stamped transaction: {:?}", blockchain.submit_transaction(stamped));
+
+    // A faucet pays newcomers, but only once per address every ten blocks
+    let mut faucet = Faucet::new(Address::from("Faucet"), 0.01, 10);
+    println!("Faucet request: {:?}", faucet.request(&mut blockchain, "Yara"));
+    println!("Faucet request: {:?}", faucet.request(&mut blockchain, "Yara"));
+
+    // Yara asks Bob for a coffee with a payment URI, which pre-fills his payment
+    let invoice = PaymentRequest {
+        address: Address::from("Yara"),
+        amount: Amount::from_coins(0.002),
+        memo: Some(String::from("Flat white & croissant")),
+    };
+    let uri = invoice.to_uri();
+    println!("Payment request: {}", uri);
+    match PaymentRequest::parse(&uri) {
+        Ok(request) => {
+            if let Some(payment) = request.to_transaction_request(Address::from("Bob"), 0.001) {
+                println!("Paid request: {:?}", blockchain.new_transactions(vec![payment]));
+            }
+        }
+        Err(error) => println!("Payment request rejected: {:?}", error),
+    }
+    println!("Payment request: {:?}", PaymentRequest::parse("bitcoin:Yara?amount=1"));
 
     // Display the entire blockchain
     println!("Blockchain: {:?}", blockchain.chain);
+    println!("Median time past: {}", blockchain.median_time_past());
+    println!("Chain valid: {}", blockchain.is_valid_chain());
+
+    // Inspect the tip straight from its encoding, without allocating
+    let tip_bytes = blockchain.last_block().serialize();
+    let tip = BlockRef::parse(&tip_bytes).expect("blocks encode canonically");
+    let senders: Vec<&str> = tip.transactions().map(|transaction| transaction.sender).collect();
+    println!("Tip read in place: height {}, senders {:?}", tip.index, senders);
+    println!(
+        "Tip survives an encoding round trip: {}",
+        tip.to_block().calculate_hash() == blockchain.last_block().calculate_hash()
+    );
+    println!("Stale blocks: {:?}", blockchain.stale_blocks());
+    println!("Chain statistics: {:?}", blockchain.stats());
+    println!("Soft-fork deployments: {:?}", blockchain.deployment_states());
+    println!("Owner of {}: {:?}", artwork, blockchain.owner_of(&artwork));
+    println!("Bob's assets: {:?}", blockchain.assets_of("Bob"));
+    println!("Name \"bob\" resolves to {:?}", blockchain.resolve_name("bob"));
+    let richest: Vec<String> = blockchain
+        .richest_accounts(5)
+        .into_iter()
+        .map(|(address, balance)| match Amount::from_coins(balance) {
+            Some(balance) => format!("{}: {}", address, balance),
+            None => format!("{}: {}", address, balance),
+        })
+        .collect();
+    println!("Richest accounts: {:?}", richest);
+    println!("Amounts: {:?}", ["1,234.5", "0.00000001", "150 units", "1,5", "0.000000001"].map(Amount::parse));
+    println!("State root at the tip: {}", blockchain.last_block().state_root);
+    let history: Vec<f64> = (0..=blockchain.last_block().index).map(|height| blockchain.balance_at("Alice", height)).collect();
+    println!("Alice's balance by height: {:?}", history);
+    println!(
+        "What changed in block 2: {:?}",
+        blockchain.state_diff(&blockchain.chain[1].calculate_hash(), &blockchain.chain[2].calculate_hash())
+    );
+    let registration = blockchain.chain[3].transactions.iter().find(|transaction| transaction.name.is_some());
+    if let Some(registration) = registration {
+        println!("Trace of Bob's name registration: {:?}", blockchain.trace_transaction(&registration.hash()));
+    }
+    println!(
+        "Estimated fee rate: {:e} per byte within 1 block, {:e} within 6",
+        blockchain.estimate_fee(1),
+        blockchain.estimate_fee(6)
+    );
+
+    // Trace who paid whom over the first three blocks
+    let heights = 1..=3;
+    println!("Activity of Alice: {:?}", analytics::activity(&blockchain, heights.clone()).get("Alice"));
+    println!("Address clusters: {:?}", analytics::clusters(&blockchain, heights.clone()));
+    let flows = analytics::value_flows(&blockchain, heights);
+    println!("Value flows: {}", analytics::flows_to_json(&flows));
+    println!("{}", analytics::flows_to_dot(&flows));
+    println!("History of Alice:\n{}", analytics::history_to_csv(&analytics::history(&blockchain, "Alice")));
+
+    // How often does a double spend beat the merchant's confirmation policy?
+    for (attacker_share, confirmations) in [(0.1, 1), (0.3, 1), (0.3, 3), (0.45, 6)] {
+        let scenario = simulation::DoubleSpendScenario {
+            attacker_share,
+            confirmations,
+            max_deficit: 10,
+            trials: 10_000,
+            seed: 7,
+        };
+        println!(
+            "Double spend with {:.0}% of hash power against {} confirmation(s): {:?}",
+            attacker_share * 100.0,
+            confirmations,
+            scenario.run()
+        );
+    }
+
+    // Withholding blocks pays off once a pool has enough hash power
+    for pool_share in [0.2, 0.3, 0.4] {
+        let revenue_share = |strategy| {
+            let scenario = simulation::MiningScenario { pool_share, tie_share: 0.5, strategy, blocks: 100_000, seed: 7 };
+            scenario.run().revenue_share
+        };
+        println!(
+            "Pool with {:.0}% of hash power earns {:.3} mining honestly, {:.3} mining selfishly",
+            pool_share * 100.0,
+            revenue_share(simulation::MinerStrategy::Honest),
+            revenue_share(simulation::MinerStrategy::Selfish)
+        );
+    }
+
+    // A peer offers a longer chain that rewrites early history; finality refuses it
+    let payment_index = blockchain.chain[1]
+        .transactions
+        .iter()
+        .position(|transaction| !transaction.outputs.is_empty())
+        .expect("the first block pays Alice");
+    let first_payment = blockchain.chain[1].transactions[payment_index].hash();
+    println!("Finalized height: {}", blockchain.finalized_height());
+    println!("Confirmations of first payment: {}", blockchain.confirmations(&first_payment));
+    let mut rewritten = blockchain.chain.clone();
+    rewritten[1].transactions[payment_index].outputs[0].recipient = Address::from("Mallory");
+    rewritten.push(blockchain.last_block().clone());
+    match blockchain.replace_chain(rewritten) {
+        Ok(()) => println!("Reorg attempt succeeded"),
+        Err(error) => println!("Reorg attempt rejected ({}): {:?}", error.code(), error),
+    }
+
+    // A tip that misreports the resulting balances is refused with a reason
+    let mut tampered = blockchain.chain.clone();
+    if let Some(tip) = tampered.last_mut() {
+        tip.state_root = state_root(&HashMap::new());
+    }
+    if let Err(invalid) = blockchain.check_chain(&tampered) {
+        println!("Block {} rejected: {}", invalid.height, invalid.reason.code());
+    }
+    blockchain.watch_transaction(first_payment, FINALITY_DEPTH);
+    println!("Chain events: {:?}", blockchain.take_events());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testutil::{generate_chain, ChainSpec, SteppingClock};
+    use super::*;
+
+    /// A plain payment, for tests that only need distinct, valid transactions
+    fn payment(sender: &str, recipient: &str, amount: f64, nonce: u64) -> Transaction {
+        Transaction {
+            sender: Address::from(sender),
+            outputs: vec![Output::new(Address::from(recipient), amount)],
+            lock_time: 0,
+            nonce,
+            fee: 0.001,
+            asset: None,
+            name: None,
+            stamp: 0,
+        }
+    }
+
+    /// A chain in which each address has mined one block, in order
+    fn funded(addresses: &[&str]) -> Blockchain {
+        let mut blockchain = Blockchain::with_clock(Box::new(SteppingClock::new(1_700_000_000, 60)));
+        for address in addresses {
+            blockchain.new_block(address, blockchain.proof_of_work(blockchain.last_block().proof));
+        }
+        blockchain
+    }
+
+    /// The sequential root computation `MerkleTree` replaced
+    fn reference_merkle_root(transactions: &[Transaction]) -> String {
+        let mut level: Vec<String> = transactions.iter().map(Transaction::hash).collect();
+        if level.is_empty() {
+            return String::from("0");
+        }
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0]))).collect();
+        }
+        level.remove(0)
+    }
+
+    #[test]
+    fn merkle_tree_matches_sequential_root() {
+        let transactions: Vec<Transaction> = (0..3001).map(|nonce| payment("Alice", "Bob", 0.01, nonce)).collect();
+        let sizes = (0..=33).chain([1023, 1024, 1025, 2047, 2048, 3001]);
+        for size in sizes {
+            let leaves = &transactions[..size];
+            assert_eq!(MerkleTree::new(leaves).root(), reference_merkle_root(leaves), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn merkle_proofs_verify_every_leaf() {
+        let transactions: Vec<Transaction> = (0..17).map(|nonce| payment("Alice", "Bob", 0.01, nonce)).collect();
+        for size in 1..=transactions.len() {
+            let tree = MerkleTree::new(&transactions[..size]);
+            for (index, transaction) in transactions[..size].iter().enumerate() {
+                let proof = tree.proof(index).expect("index is within the tree");
+                assert!(verify_merkle_proof(&transaction.hash(), &proof, &tree.root()));
+                if size > 1 {
+                    let other = &transactions[(index + 1) % size];
+                    assert!(!verify_merkle_proof(&other.hash(), &proof, &tree.root()));
+                }
+            }
+            assert!(tree.proof(size).is_none());
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips_through_borrowed_views() {
+        let blockchain = generate_chain(&ChainSpec { blocks: 4, fork_every: Some(2), ..ChainSpec::default() });
+        let mint = Transaction {
+            asset: Some(AssetOperation::Mint { metadata_hash: String::from("ab") }),
+            outputs: Vec::new(),
+            ..payment("Alice", "Bob", 0.01, 0)
+        };
+        let register = Transaction {
+            name: Some(NameOperation::Register { name: String::from("alice"), target: Address::from("Alice") }),
+            outputs: Vec::new(),
+            ..payment("Alice", "Bob", 0.01, 1)
+        };
+        let transactions = blockchain
+            .chain
+            .iter()
+            .chain(blockchain.stale_blocks())
+            .flat_map(|block| block.transactions.iter().cloned())
+            .chain([mint, register]);
+        for transaction in transactions {
+            let bytes = transaction.serialize();
+            let view = TransactionRef::parse(&bytes).expect("transactions encode canonically");
+            assert_eq!(bytes.len(), transaction.vsize());
+            assert_eq!(view.to_transaction(), transaction);
+            let mut trailing = bytes.clone();
+            trailing.push(0);
+            assert!(TransactionRef::parse(&trailing).is_none());
+            assert!(TransactionRef::parse(&bytes[..bytes.len() - 1]).is_none());
+        }
+        for block in blockchain.chain.iter().chain(blockchain.stale_blocks()) {
+            let bytes = block.serialize();
+            let view = BlockRef::parse(&bytes).expect("blocks encode canonically");
+            assert_eq!(view.transactions().count(), block.transactions.len());
+            assert_eq!(view.to_block().serialize(), bytes);
+        }
+    }
+
+    #[test]
+    fn generated_chains_are_deterministic() {
+        let spec = ChainSpec { seed: 3, blocks: 3, fork_every: Some(1), ..ChainSpec::default() };
+        let first = generate_chain(&spec);
+        let second = generate_chain(&spec);
+        assert_eq!(first.last_block().calculate_hash(), second.last_block().calculate_hash());
+        assert_eq!(first.stale_blocks().len(), 3);
+        assert!(first.is_valid_chain());
+        let invalid = generate_chain(&ChainSpec { invalid_tip: true, ..spec });
+        assert!(!invalid.is_valid_chain());
+    }
+
+    #[test]
+    fn generated_chains_respect_sender_limits() {
+        let blockchain = generate_chain(&ChainSpec { blocks: 2, transactions_per_block: 300, ..ChainSpec::default() });
+        assert!(blockchain.is_valid_chain());
+        let limit = blockchain.relay_policy.max_pending_transactions;
+        for sender in ["Alice", "Bob", "Charlie", "David", "Eve", "Frank", "Grace", "Heidi"] {
+            let pending = blockchain.current_transactions.iter().filter(|pending| pending.sender == sender).count();
+            assert!(pending <= limit, "{} has {} pending", sender, pending);
+        }
+    }
+
+    #[test]
+    fn local_relay_policy_does_not_change_chain_validity() {
+        let mut blockchain = generate_chain(&ChainSpec::default());
+        assert!(blockchain.is_valid_chain());
+        blockchain.relay_policy.min_fee_rate = 1.0;
+        blockchain.relay_policy.dust_threshold = 10.0;
+        assert!(blockchain.is_valid_chain());
+        let mut fresh = Blockchain::new();
+        fresh.relay_policy = blockchain.relay_policy;
+        assert_eq!(fresh.check_chain(&blockchain.chain), Ok(()));
+    }
+
+    #[test]
+    fn mined_blocks_pass_validation() {
+        let mut blockchain = funded(&["Alice"]);
+        assert_eq!(
+            blockchain.new_transaction(Address::from("Alice"), Address::from("Bob"), 0.0005),
+            Err(MempoolError::DustOutput)
+        );
+        // A transaction the consensus rules refuse, as if left over from before a reorg
+        let dust = Transaction { fee: 0.0, ..payment("Alice", "Bob", 0.0005, 0) };
+        blockchain.current_transactions.push(dust.clone());
+        let proof = blockchain.proof_of_work(blockchain.last_block().proof);
+        let block = blockchain.new_block("Miner", proof);
+        assert!(!block.transactions.contains(&dust));
+        assert_eq!(blockchain.check_chain(&blockchain.chain), Ok(()));
+    }
+
+    #[test]
+    fn mined_nonces_cannot_be_reused() {
+        let mut blockchain = funded(&["Alice"]);
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.1, 0)).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(
+            blockchain.submit_transaction(payment("Alice", "Mallory", 0.1, 0)),
+            Err(MempoolError::NonceTooLow { next_nonce: 1 })
+        );
+
+        // A gap in the sender's nonces keeps the later transaction out of blocks
+        blockchain.current_transactions.push(payment("Alice", "Mallory", 0.1, 0));
+        blockchain.submit_transaction(payment("Alice", "Charlie", 0.1, 2)).unwrap();
+        let block = blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(block.transactions.len(), 1, "only the coinbase");
+        assert!(blockchain.is_valid_chain());
+
+        let mut replayed = blockchain.chain.clone();
+        replayed[3].transactions.push(payment("Alice", "Mallory", 0.1, 0));
+        assert_eq!(
+            blockchain.check_chain(&replayed),
+            Err(InvalidBlock { height: 3, reason: BlockRejection::BadNonce })
+        );
+    }
+
+    #[test]
+    fn reorg_drops_transactions_whose_nonce_the_new_chain_spent() {
+        let mut ours = funded(&["Alice"]);
+        let mut theirs = funded(&["Alice"]);
+
+        let to_bob = payment("Alice", "Bob", 0.1, 0);
+        ours.submit_transaction(to_bob.clone()).unwrap();
+        ours.new_block("Miner", ours.proof_of_work(ours.last_block().proof));
+        theirs.submit_transaction(payment("Alice", "Mallory", 0.1, 0)).unwrap();
+        theirs.new_block("Miner", theirs.proof_of_work(theirs.last_block().proof));
+        theirs.new_block("Miner", theirs.proof_of_work(theirs.last_block().proof));
+
+        ours.replace_chain(theirs.chain.clone()).unwrap();
+        assert!(ours.current_transactions.is_empty());
+        assert!(matches!(ours.transaction_status(&to_bob), TransactionStatus::Conflicted { .. }));
+        ours.new_block("Miner", ours.proof_of_work(ours.last_block().proof));
+        assert!(ours.is_valid_chain());
+    }
+
+    #[test]
+    fn dropped_addresses_leave_the_interner() {
+        let kept = Address::from("Alice");
+        for index in 0..10_000 {
+            Address::new(&format!("account-{}", index));
+        }
+        assert!(ADDRESSES.with(|addresses| addresses.borrow().entries) <= 65);
+        assert!(Arc::ptr_eq(&kept.0, &Address::from("Alice").0));
+    }
+
+    #[test]
+    fn refused_inheritance_stays_scheduled() {
+        let mut blockchain = funded(&["Alice"]);
+        let outputs = vec![Output { recipient: Address::from("Bob"), amount: 0.1 }];
+        let schedule_id = blockchain.schedule_inheritance(Address::from("Alice"), outputs, 0.001, 1).unwrap();
+        blockchain.relay_policy.min_fee_rate = 1.0;
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.scheduled_payments.len(), 1);
+        assert!(blockchain.current_transactions.is_empty());
+
+        blockchain.relay_policy = RelayPolicy::default();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert!(blockchain.scheduled_payments.is_empty());
+        assert!(blockchain.events.iter().any(|event| matches!(
+            event,
+            ChainEvent::ScheduledPaymentSubmitted { schedule_id: id, .. } if *id == schedule_id
+        )));
+    }
+
+    #[test]
+    fn mempool_entries_report_their_age() {
+        let mut blockchain = funded(&["Alice", "Carol"]);
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.1, 0)).unwrap();
+        blockchain.submit_transaction(payment("Carol", "Bob", 0.1, 0)).unwrap();
+        let view = blockchain.get_mempool(&MempoolFilter::default(), Page { number: 0, size: 10 });
+        let age = |sender: &str| view.entries.iter().find(|entry| entry.transaction.sender == sender).unwrap().age;
+        assert!(age("Alice") > age("Carol"));
+        assert!(age("Carol") > 0);
+
+        let view = blockchain.get_mempool(&MempoolFilter::default(), Page { number: usize::MAX, size: 10 });
+        assert!(view.entries.is_empty());
+        assert_eq!(view.summary.count, 2);
+    }
+
+    #[test]
+    fn aborted_batches_keep_entry_times() {
+        let mut blockchain = funded(&["Alice"]);
+        let original = payment("Alice", "Bob", 0.1, 0);
+        blockchain.submit_transaction(original.clone()).unwrap();
+        let pending_since = blockchain.pending_since.clone();
+
+        let request = |recipient: &str, fee, nonce| TransactionRequest {
+            sender: Address::from("Alice"),
+            outputs: vec![Output::new(Address::from(recipient), 0.1)],
+            fee,
+            lock_time: 0,
+            nonce,
+        };
+        // The first request replaces the pending payment before the second fails
+        let results = blockchain.new_transactions(vec![request("Bob", 0.005, Some(0)), request("", 0.001, None)]);
+        assert_eq!(results, vec![Err(MempoolError::BatchAborted), Err(MempoolError::MissingAddress)]);
+        assert_eq!(blockchain.current_transactions, vec![original]);
+        assert_eq!(blockchain.pending_since, pending_since);
+    }
+
+    #[test]
+    fn deployments_time_out_before_starting() {
+        let blockchain = generate_chain(&ChainSpec { blocks: 2, transactions_per_block: 0, ..ChainSpec::default() });
+        let late = Deployment { name: "late", bit: 1, start_height: 20, timeout_height: 10 };
+        assert_eq!(blockchain.deployment_state(&late, 9), DeploymentState::Defined);
+        assert_eq!(blockchain.deployment_state(&late, 10), DeploymentState::Failed);
+        let on_time = Deployment { timeout_height: 30, ..late };
+        assert_eq!(blockchain.deployment_state(&on_time, 10), DeploymentState::Defined);
+        assert_eq!(blockchain.deployment_state(&COINBASE_HEIGHT, 10), DeploymentState::Started);
+    }
+
+    #[test]
+    fn coinbase_height_is_enforced_once_active() {
+        let blockchain = generate_chain(&ChainSpec { blocks: 22, transactions_per_block: 0, ..ChainSpec::default() });
+        assert_eq!(blockchain.deployment_state(&COINBASE_HEIGHT, 29), DeploymentState::LockedIn);
+        assert_eq!(blockchain.deployment_state(&COINBASE_HEIGHT, 30), DeploymentState::Active);
+        assert!(blockchain.is_valid_chain());
+
+        let mut chain = blockchain.chain.clone();
+        chain[29].transactions[0].nonce = 0;
+        assert_eq!(blockchain.check_chain(&chain[..30]), Ok(()));
+        chain[30].transactions[0].nonce = 0;
+        chain[30].previous_hash = chain[29].calculate_hash();
+        assert_eq!(blockchain.check_chain(&chain), Err(InvalidBlock { height: 30, reason: BlockRejection::BadCoinbase }));
+    }
+
+    #[test]
+    fn block_caches_forget_the_oldest_hash() {
+        let mut cache = BlockHashCache::new(2);
+        for hash in ["a", "b", "a", "c"] {
+            cache.insert(String::from(hash));
+        }
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b") && cache.contains("c"));
+        assert_eq!(cache.order.len(), 2);
+
+        let mut blockchain = funded(&["Alice", "Bob", "Carol"]);
+        blockchain.validated_blocks = RefCell::new(BlockHashCache::new(1));
+        assert!(blockchain.is_valid_chain());
+        assert!(blockchain.is_valid_chain());
+        assert_eq!(blockchain.validated_blocks.borrow().hashes.len(), 1);
+    }
+
+    #[test]
+    fn faucet_pays_at_least_the_consensus_fee() {
+        let mut blockchain = funded(&["Faucet"]);
+        blockchain.relay_policy.min_fee_rate = 0.0;
+        let mut faucet = Faucet::new(Address::from("Faucet"), 0.1, 3);
+        faucet.request(&mut blockchain, "Student").unwrap();
+        let paid = blockchain.current_transactions[0].clone();
+        assert_eq!(paid.fee, RelayPolicy::CONSENSUS.min_fee(&paid));
+        let block = blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert!(block.transactions.contains(&paid));
+        assert_eq!(
+            faucet.request(&mut blockchain, "Student"),
+            Err(FaucetError::RateLimited { retry_height: 4 })
+        );
+    }
+
+    #[test]
+    fn amounts_reject_leading_zero_groups() {
+        assert_eq!(Amount::parse("00,123"), Err(AmountError::MisplacedSeparator));
+        assert_eq!(Amount::parse("0,100"), Err(AmountError::MisplacedSeparator));
+        assert_eq!(Amount::parse("10,100"), Ok(Amount(10_100 * Amount::UNITS_PER_COIN)));
+        assert_eq!(Amount::parse("0.5"), Ok(Amount(Amount::UNITS_PER_COIN / 2)));
+        assert_eq!(format!("{:#}", Amount(-12_345 * Amount::UNITS_PER_COIN / 10)), "-1234.5");
+    }
+
+    #[test]
+    fn history_exports_exact_amounts() {
+        let mut blockchain = funded(&["Alice"]);
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.1, 0)).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+
+        let history = analytics::history(&blockchain, "Alice");
+        let csv = analytics::history_to_csv(&history);
+        assert!(csv.lines().nth(1).unwrap().ends_with(",1,0,0,1"));
+        assert!(csv.lines().nth(2).unwrap().ends_with(",-0.1,0.001,Bob,0.899"));
+        assert!(analytics::history_to_json(&history).ends_with("\"amount\":-0.1,\"fee\":0.001,\"counterparties\":[\"Bob\"],\"balance\":0.899}]"));
+    }
+
+    #[test]
+    fn payment_requests_round_trip() {
+        for amount in [Amount(1), Amount(12_345 * Amount::UNITS_PER_COIN / 10), Amount(i64::MAX)] {
+            let request = PaymentRequest {
+                address: Address::from("Yara & co"),
+                amount: Some(amount),
+                memo: Some(String::from("50% now")),
+            };
+            assert_eq!(PaymentRequest::parse(&request.to_uri()), Ok(request));
+        }
+    }
+
+    #[test]
+    fn double_spend_rejects_impossible_shares() {
+        let scenario = |attacker_share| simulation::DoubleSpendScenario {
+            attacker_share,
+            confirmations: 3,
+            max_deficit: 10,
+            trials: 100,
+            seed: 7,
+        };
+        for attacker_share in [1.0, 1.5, -0.1, f64::NAN] {
+            assert_eq!(scenario(attacker_share).run(), None);
+        }
+        assert_eq!(scenario(0.0).run().map(|report| report.successes), Some(0));
+        assert!(scenario(0.3).run().is_some());
+    }
+
+    #[test]
+    fn spends_must_be_covered_by_confirmed_funds() {
+        let mut blockchain = funded(&["Alice"]);
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.6, 0)).unwrap();
+        let overspend = blockchain.submit_transaction(payment("Alice", "Bob", 0.5, 1));
+        assert_eq!(overspend, Err(MempoolError::InsufficientFunds));
+        assert_eq!(overspend.unwrap_err().code(), "insufficient-funds");
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.398, 1)).unwrap();
+        assert_eq!(
+            blockchain.submit_transaction(payment("Bob", "Carol", 0.1, 0)),
+            Err(MempoolError::InsufficientFunds)
+        );
+
+        // Only confirmed funds count, so an unfunded leftover is never mined
+        blockchain.current_transactions.push(payment("Mallory", "Carol", 0.1, 0));
+        let block = blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(block.transactions.len(), 3);
+        assert_eq!(blockchain.balance_at("Alice", 2), 0.0);
+        assert!(blockchain.is_valid_chain());
+
+        let mut replayed = blockchain.chain.clone();
+        replayed[2].transactions.push(payment("Mallory", "Carol", 0.1, 0));
+        assert_eq!(
+            blockchain.check_chain(&replayed),
+            Err(InvalidBlock { height: 2, reason: BlockRejection::InsufficientFunds })
+        );
+    }
+
+    #[test]
+    fn only_blocks_mint_coins() {
+        let mut blockchain = funded(&["Alice"]);
+        assert_eq!(
+            blockchain.new_transaction(Address::from("0"), Address::from("Mallory"), 1.0),
+            Err(MempoolError::ReservedSender)
+        );
+        let mint = Transaction { fee: 0.0, ..payment("0", "Mallory", 1.0, 0) };
+        assert_eq!(blockchain.submit_transaction(mint.clone()).map_err(|error| error.code()), Err("reserved-sender"));
+        assert!(blockchain.current_transactions.is_empty());
+
+        let block = blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(block.transactions[0].sender, "0");
+        assert_eq!(blockchain.balance_at("Miner", 2), MINING_REWARD);
+        assert_eq!(blockchain.balance_at("Mallory", 2), 0.0);
+    }
+
+    #[test]
+    fn blocks_carry_one_bounded_coinbase() {
+        let mut blockchain = funded(&["Alice"]);
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.1, 0)).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.balance_at("Miner", 2), MINING_REWARD + 0.001);
+        assert_eq!(blockchain.check_chain(&blockchain.chain), Ok(()));
+
+        let tampered = |change: fn(&mut Vec<Transaction>)| {
+            let mut chain = blockchain.chain.clone();
+            change(&mut chain[2].transactions);
+            blockchain.check_chain(&chain)
+        };
+        let bad_coinbase = Err(InvalidBlock { height: 2, reason: BlockRejection::BadCoinbase });
+        assert_eq!(tampered(|transactions| transactions[0].outputs[0].amount += 0.000_000_01), bad_coinbase);
+        assert_eq!(tampered(|transactions| transactions.push(payment("0", "Mallory", 0.1, 0))), bad_coinbase);
+        assert_eq!(tampered(|transactions| drop(transactions.remove(0))), bad_coinbase);
+        assert_eq!(tampered(|transactions| transactions.swap(0, 1)), bad_coinbase);
+        assert_eq!(BlockRejection::BadCoinbase.code(), "bad-coinbase");
+
+        // A coinbase may leave part of the reward unclaimed
+        let mut chain = blockchain.chain.clone();
+        chain[2].transactions[0].outputs[0].amount = 0.5;
+        assert_eq!(blockchain.check_chain(&chain), Err(InvalidBlock { height: 2, reason: BlockRejection::BadStateRoot }));
+    }
+
+    #[test]
+    fn block_size_counts_the_coinbase() {
+        let full = generate_chain(&ChainSpec { blocks: 2, transactions_per_block: 300, ..ChainSpec::default() });
+        assert!(!full.current_transactions.is_empty());
+        for block in &full.chain {
+            assert!(block.transactions.iter().map(Transaction::vsize).sum::<usize>() <= MAX_BLOCK_VSIZE);
+        }
+
+        let mut filler = payment("Alice", "", 0.1, 0);
+        filler.outputs[0].recipient = Address::new(&"B".repeat(MAX_BLOCK_VSIZE - filler.vsize()));
+        assert_eq!(filler.vsize(), MAX_BLOCK_VSIZE);
+        let mut block = funded(&["Alice"]).chain[1].clone();
+        block.transactions.push(filler);
+        assert_eq!(block.check(), Err(BlockRejection::TooLarge));
+    }
+
+    #[test]
+    fn submitted_blocks_are_validated() {
+        let mut blockchain = funded(&["Alice"]);
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.1, 0)).unwrap();
+        let mut inflated = blockchain.build_block_template("Mallory");
+        inflated.coinbase.outputs[0].amount += 1.0;
+        let proof = blockchain.proof_of_work(inflated.last_proof);
+        let rejected = blockchain.submit_block(inflated, proof).unwrap_err();
+        assert_eq!(rejected, SubmitBlockError::Rejected(BlockRejection::BadCoinbase));
+        assert_eq!(rejected.code(), "bad-coinbase");
+        assert_eq!(blockchain.chain.len(), 2);
+        assert_eq!(blockchain.current_transactions.len(), 1);
+
+        let template = blockchain.build_block_template("Mallory");
+        let block = blockchain.submit_block(template, proof).unwrap();
+        assert_eq!(blockchain.last_block().calculate_hash(), block.calculate_hash());
+        assert!(blockchain.current_transactions.is_empty());
+        assert_eq!(blockchain.balance_at("Mallory", 2), MINING_REWARD + 0.001);
+    }
+
+    #[test]
+    fn snapshots_match_full_replay() {
+        let mut blockchain = Blockchain::with_clock(Box::new(SteppingClock::new(1_700_000_000, 60)));
+        let addresses = ["Alice", "Bob", "Charlie", "David"];
+        for height in 1..=250 {
+            let sender = addresses[(height + 1) % 4];
+            if height > 4 {
+                blockchain
+                    .new_transaction(Address::from(sender), Address::from(addresses[(height + 2) % 4]), 0.01)
+                    .unwrap();
+            }
+            blockchain.new_block(addresses[height % 4], 0);
+        }
+        let replay = |height: u64| {
+            let mut accounts = HashMap::new();
+            for transaction in blockchain.chain[..=height as usize].iter().flat_map(|block| &block.transactions) {
+                apply_payment(&mut accounts, transaction);
+            }
+            accounts
+        };
+        for height in [0, 1, 99, 100, 101, 150, 200, 201, 250, 250, 100] {
+            assert_eq!(blockchain.account_states(height), replay(height), "height {}", height);
+        }
+        assert_eq!(blockchain.state_snapshots.borrow().len(), 2);
+    }
+
+    #[test]
+    fn replacements_must_outbid_the_original() {
+        let mut blockchain = funded(&["Alice"]);
+        let original = payment("Alice", "Bob", 0.1, 0);
+        blockchain.submit_transaction(original.clone()).unwrap();
+        let underbid = Transaction { fee: 0.0015, ..payment("Alice", "Carol", 0.1, 0) };
+        assert_eq!(
+            blockchain.submit_transaction(underbid),
+            Err(MempoolError::ReplacementFeeTooLow { required: 0.002 })
+        );
+        assert_eq!(blockchain.current_transactions, vec![original.clone()]);
+
+        let replacement = Transaction { fee: 0.002, ..payment("Alice", "Carol", 0.1, 0) };
+        blockchain.submit_transaction(replacement.clone()).unwrap();
+        assert_eq!(blockchain.current_transactions, vec![replacement.clone()]);
+        assert_eq!(
+            blockchain.transaction_status(&original),
+            TransactionStatus::Conflicted { by: replacement.hash() }
+        );
+        assert!(matches!(
+            blockchain.take_events().last(),
+            Some(ChainEvent::TransactionReplaced { replaced, replacement: by })
+                if **replaced == original && **by == replacement
+        ));
+
+        blockchain.replacement_policy.enabled = false;
+        let outbid = Transaction { fee: 0.01, ..original };
+        assert_eq!(blockchain.submit_transaction(outbid), Err(MempoolError::Conflict));
+    }
+
+    #[test]
+    fn high_fee_children_pull_in_their_parents() {
+        let mut blockchain = funded(&["Alice", "Bob"]);
+        let mut parent = payment("Alice", "Carol", 0.1, 0);
+        parent.fee = blockchain.min_fee(&parent);
+        let child = Transaction { fee: 0.01, ..payment("Alice", "Carol", 0.1, 1) };
+        let other = payment("Bob", "Carol", 0.1, 0);
+        for transaction in [&parent, &child, &other] {
+            blockchain.submit_transaction(transaction.clone()).unwrap();
+        }
+        let median_time_past = blockchain.median_time_past();
+        assert_eq!(
+            blockchain.select_transactions(median_time_past, 0),
+            vec![parent.clone(), child.clone(), other.clone()]
+        );
+
+        // With room for one transaction the child cannot go without its
+        // parent, and the parent alone pays less than Bob
+        let one_left = MAX_BLOCK_VSIZE - parent.vsize().max(other.vsize());
+        assert_eq!(blockchain.select_transactions(median_time_past, one_left), vec![other]);
+    }
+
+    #[test]
+    fn batches_are_admitted_all_or_nothing() {
+        let mut blockchain = funded(&["Alice"]);
+        let request = |recipient: &str| TransactionRequest {
+            sender: Address::from("Alice"),
+            outputs: vec![Output::new(Address::from(recipient), 0.1)],
+            fee: 0.001,
+            lock_time: 0,
+            nonce: None,
+        };
+        let results = blockchain.new_transactions(vec![request("Bob"), request("Carol")]);
+        let txids: Vec<TxId> = blockchain.current_transactions.iter().map(Transaction::hash).collect();
+        assert_eq!(results, txids.into_iter().map(Ok).collect::<Vec<_>>());
+        assert_eq!(blockchain.current_transactions.iter().map(|pending| pending.nonce).collect::<Vec<_>>(), [0, 1]);
+
+        let histogram = blockchain.fee_histogram.counts();
+        let results = blockchain.new_transactions(vec![request("Dave"), request(""), request("Erin")]);
+        assert_eq!(
+            results,
+            vec![Err(MempoolError::BatchAborted), Err(MempoolError::MissingAddress), Err(MempoolError::BatchAborted)]
+        );
+        assert_eq!(blockchain.current_transactions.len(), 2);
+        assert_eq!(blockchain.fee_histogram.counts(), histogram);
+        assert_eq!(blockchain.next_nonce("Alice"), 2);
+    }
+
+    #[test]
+    fn reorgs_stop_at_the_finalized_height() {
+        let mine = |blockchain: &mut Blockchain, miner: &str, blocks: usize| {
+            for _ in 0..blocks {
+                blockchain.new_block(miner, blockchain.proof_of_work(blockchain.last_block().proof));
+            }
+        };
+        let mut ours = funded(&["Alice"]);
+        let to_bob = payment("Alice", "Bob", 0.1, 0);
+        ours.submit_transaction(to_bob.clone()).unwrap();
+        mine(&mut ours, "Miner", 1);
+        let mut theirs = funded(&["Alice"]);
+        mine(&mut theirs, "Rival", 2);
+        assert_eq!(ours.replace_chain(theirs.chain[..3].to_vec()), Err(ReorgError::NotLonger));
+
+        ours.replace_chain(theirs.chain.clone()).unwrap();
+        assert_eq!(ours.confirmations(&to_bob.hash()), 0);
+        assert_eq!(ours.transaction_status(&to_bob), TransactionStatus::Pending);
+        assert_eq!(ours.stale_blocks().len(), 1);
+
+        // Six blocks on top of the fork point finalize it
+        mine(&mut ours, "Miner", 6);
+        assert_eq!(ours.finalized_height(), 3);
+        assert_eq!(ours.confirmations(&to_bob.hash()), 6);
+        let mut rival = funded(&["Alice"]);
+        mine(&mut rival, "Other", 9);
+        assert_eq!(
+            ours.replace_chain(rival.chain.clone()),
+            Err(ReorgError::ForkBelowFinality { fork_height: 2, finalized_height: 3 })
+        );
+        assert_eq!(ours.last_block().index, 9);
+    }
+
+    #[test]
+    fn watched_transactions_report_reaching_and_losing_their_target() {
+        let mut ours = funded(&["Alice"]);
+        let to_bob = payment("Alice", "Bob", 0.1, 0);
+        ours.submit_transaction(to_bob.clone()).unwrap();
+        ours.watch_transaction(to_bob.hash(), 2);
+        ours.new_block("Miner", ours.proof_of_work(ours.last_block().proof));
+        assert_eq!(ours.confirmations(&to_bob.hash()), 1);
+        assert!(ours.take_events().is_empty());
+        ours.new_block("Miner", ours.proof_of_work(ours.last_block().proof));
+        assert_eq!(
+            ours.take_events(),
+            vec![ChainEvent::TransactionConfirmed { txid: to_bob.hash(), confirmations: 2 }]
+        );
+
+        let mut theirs = funded(&["Alice"]);
+        for _ in 0..3 {
+            theirs.new_block("Rival", theirs.proof_of_work(theirs.last_block().proof));
+        }
+        ours.replace_chain(theirs.chain.clone()).unwrap();
+        assert_eq!(
+            ours.take_events(),
+            vec![ChainEvent::ConfirmationsDropped { txid: to_bob.hash(), confirmations: 0 }]
+        );
+    }
+
+    #[test]
+    fn recurring_payments_repeat_until_cancelled() {
+        let mut blockchain = funded(&["Alice"]);
+        let rent = vec![Output::new(Address::from("Landlord"), 0.05)];
+        let dust = vec![Output::new(Address::from("Landlord"), 0.0001)];
+        assert_eq!(blockchain.schedule_payment(Address::from("Alice"), dust, 0.001, 2), Err(MempoolError::DustOutput));
+        let schedule_id = blockchain.schedule_payment(Address::from("Alice"), rent, 0.001, 2).unwrap();
+
+        let mut submitted = Vec::new();
+        for _ in 0..5 {
+            blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+            let height = blockchain.last_block().index;
+            for event in blockchain.take_events() {
+                if let ChainEvent::ScheduledPaymentSubmitted { schedule_id: id, .. } = event {
+                    assert_eq!(id, schedule_id);
+                    submitted.push(height);
+                }
+            }
+        }
+        assert_eq!(submitted, [3, 5]);
+        assert_eq!(blockchain.next_nonce("Alice"), 2);
+
+        assert!(blockchain.cancel_scheduled_payment(schedule_id));
+        assert!(!blockchain.cancel_scheduled_payment(schedule_id));
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert!(blockchain.current_transactions.is_empty());
+        assert_eq!(blockchain.balance_at("Landlord", 8), 0.1);
+    }
+
+    #[test]
+    fn inheritance_waits_for_the_sender_to_go_quiet() {
+        let mut blockchain = funded(&["Alice"]);
+        let heir = vec![Output::new(Address::from("Erin"), 0.5)];
+        let nobody = vec![Output::new(Address::from(""), 0.5)];
+        assert_eq!(
+            blockchain.schedule_inheritance(Address::from("Alice"), nobody, 0.001, 3),
+            Err(MempoolError::MissingAddress)
+        );
+        let schedule_id = blockchain.schedule_inheritance(Address::from("Alice"), heir, 0.001, 3).unwrap();
+
+        // Spending at height 2 restarts the countdown
+        blockchain.submit_transaction(payment("Alice", "Bob", 0.1, 0)).unwrap();
+        let mut submitted = Vec::new();
+        for _ in 0..6 {
+            blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+            let height = blockchain.last_block().index;
+            for event in blockchain.take_events() {
+                if let ChainEvent::ScheduledPaymentSubmitted { schedule_id: id, .. } = event {
+                    assert_eq!(id, schedule_id);
+                    submitted.push(height);
+                }
+            }
+        }
+        assert_eq!(submitted, [5]);
+        assert!(blockchain.scheduled_payments.is_empty());
+        assert_eq!(blockchain.balance_at("Erin", 7), 0.5);
+    }
+
+    #[test]
+    fn assets_change_hands_only_by_their_owner() {
+        let mut blockchain = funded(&["Alice", "Bob"]);
+        let operation = |sender: &str, nonce, asset| Transaction {
+            asset: Some(asset),
+            outputs: Vec::new(),
+            ..payment(sender, sender, 0.0, nonce)
+        };
+        let mint = operation("Alice", 0, AssetOperation::Mint { metadata_hash: String::from("ab") });
+        let asset_id = mint.hash();
+        blockchain.submit_transaction(mint).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.owner_of(&asset_id), Some(Address::from("Alice")));
+        assert_eq!(blockchain.assets_of("Alice"), vec![asset_id.clone()]);
+
+        let transfer = |sender, nonce, recipient: &str| {
+            let recipient = Address::from(recipient);
+            operation(sender, nonce, AssetOperation::Transfer { asset_id: asset_id.clone(), recipient })
+        };
+        assert_eq!(blockchain.submit_transaction(transfer("Bob", 0, "Bob")), Err(MempoolError::NotAssetOwner));
+        let unknown = operation("Alice", 1, AssetOperation::Burn { asset_id: String::from("cd") });
+        assert_eq!(blockchain.submit_transaction(unknown), Err(MempoolError::UnknownAsset));
+        blockchain.submit_transaction(transfer("Alice", 1, "Bob")).unwrap();
+        assert_eq!(blockchain.submit_transaction(transfer("Alice", 2, "Carol")), Err(MempoolError::AssetBusy));
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.owner_of(&asset_id), Some(Address::from("Bob")));
+        assert!(blockchain.assets_of("Alice").is_empty());
+
+        blockchain.submit_transaction(operation("Bob", 0, AssetOperation::Burn { asset_id: asset_id.clone() })).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.owner_of(&asset_id), None);
+        assert!(blockchain.is_valid_chain());
+    }
+
+    #[test]
+    fn names_are_first_come_first_served_until_they_expire() {
+        let mut blockchain = funded(&["Alice", "Bob"]);
+        let operation = |sender: &str, nonce, name| Transaction {
+            name: Some(name),
+            outputs: Vec::new(),
+            ..payment(sender, sender, 0.0, nonce)
+        };
+        let register = |sender: &str, nonce, name: &str| {
+            let name = NameOperation::Register { name: String::from(name), target: Address::from(sender) };
+            operation(sender, nonce, name)
+        };
+        assert_eq!(blockchain.submit_transaction(register("Alice", 0, "Alice!")), Err(MempoolError::InvalidName));
+        let alice = register("Alice", 0, "alice");
+        blockchain.submit_transaction(alice.clone()).unwrap();
+        assert_eq!(blockchain.submit_transaction(register("Bob", 0, "alice")), Err(MempoolError::NameBusy));
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.resolve_name("alice"), Some(Address::from("Alice")));
+
+        assert_eq!(blockchain.submit_transaction(register("Bob", 0, "alice")), Err(MempoolError::NameTaken));
+        let renew = |sender| operation(sender, 0, NameOperation::Renew { name: String::from("alice") });
+        assert_eq!(blockchain.submit_transaction(renew("Bob")), Err(MempoolError::NotNameOwner));
+        let transfer = NameOperation::Transfer { name: String::from("alice"), recipient: Address::from("Bob") };
+        blockchain.submit_transaction(operation("Alice", 1, transfer)).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert_eq!(blockchain.lookup_name("alice").map(|record| record.owner), Some(Address::from("Bob")));
+        assert_eq!(blockchain.resolve_name("alice"), Some(Address::from("Alice")));
+
+        // A registration lapses `NAME_REGISTRATION_PERIOD` blocks after the block that made it
+        let mut names = NameRegistry::default();
+        names.apply(&alice, 3).unwrap();
+        let expiry = 3 + NAME_REGISTRATION_PERIOD;
+        assert_eq!(names.check(&register("Bob", 0, "alice"), expiry), Err(MempoolError::NameTaken));
+        assert_eq!(names.check(&register("Bob", 0, "alice"), expiry + 1), Ok(()));
+        assert_eq!(names.check(&renew("Alice"), expiry + 1), Err(MempoolError::UnknownName));
+    }
+
+    #[test]
+    fn fee_estimates_follow_the_mempool_and_recent_blocks() {
+        let mut blockchain = funded(&["Alice"]);
+        let floor = blockchain.relay_policy.min_fee_rate;
+        assert_eq!(blockchain.estimate_fee(1), floor);
+
+        blockchain.relay_policy.max_pending_transactions = usize::MAX;
+        blockchain.relay_policy.max_pending_value = f64::INFINITY;
+        let low = payment("Alice", "Bob", 0.001, 0);
+        let high = Transaction { fee: 0.002, ..payment("Alice", "Bob", 0.001, 100) };
+        for nonce in 0..200 {
+            let fee = if nonce < 100 { low.fee } else { high.fee };
+            blockchain.submit_transaction(Transaction { fee, ..payment("Alice", "Bob", 0.001, nonce) }).unwrap();
+        }
+        // One block holds every high-fee transaction but not all the low-fee ones
+        let next_block = blockchain.estimate_fee(1);
+        assert!(low.fee_rate() < next_block && next_block <= high.fee_rate());
+        assert_eq!(blockchain.estimate_fee(2), floor);
+
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert!(blockchain.current_transactions.len() < 100);
+        assert_eq!(blockchain.estimate_fee(2), low.fee_rate());
+    }
+
+    #[test]
+    fn selfish_mining_pays_only_above_the_threshold() {
+        let scenario = |pool_share, strategy| simulation::MiningScenario {
+            pool_share,
+            tie_share: 0.5,
+            strategy,
+            blocks: 100_000,
+            seed: 11,
+        };
+        let honest = scenario(0.4, simulation::MinerStrategy::Honest).run();
+        assert_eq!(honest.orphaned_blocks, 0);
+        assert!((honest.revenue_share - 0.4).abs() < 0.01);
+
+        let strong = scenario(0.4, simulation::MinerStrategy::Selfish).run();
+        assert_eq!(strong.pool_blocks + strong.honest_blocks + strong.orphaned_blocks, 100_000);
+        assert!(strong.orphaned_blocks > 0);
+        assert!(strong.revenue_share > 0.45);
+
+        // Below a quarter of the hash power, withholding loses revenue
+        let weak = scenario(0.1, simulation::MinerStrategy::Selfish).run();
+        assert!(weak.revenue_share < 0.1);
+    }
+
+    #[test]
+    fn fee_histogram_tracks_the_pending_list() {
+        let mut blockchain = funded(&["Alice", "Carol"]);
+        let bucket = |transaction: &Transaction| FeeHistogram::bucket(transaction) as f64 * FEE_HISTOGRAM_BUCKET;
+        let cheap = payment("Alice", "Bob", 0.1, 0);
+        let dear = Transaction { fee: 0.01, ..payment("Carol", "Bob", 0.1, 0) };
+        blockchain.submit_transaction(cheap.clone()).unwrap();
+        blockchain.submit_transaction(dear.clone()).unwrap();
+        assert_eq!(blockchain.fee_histogram.counts(), [(bucket(&dear), 1), (bucket(&cheap), 1)]);
+        assert_eq!(blockchain.fee_histogram.rate_to_fit(cheap.vsize() + dear.vsize()), 0.0);
+        assert_eq!(blockchain.fee_histogram.rate_to_fit(dear.vsize()), bucket(&cheap) + FEE_HISTOGRAM_BUCKET);
+
+        // A replacement moves its sender's entry to the new rate
+        let bumped = Transaction { fee: 0.01, ..cheap.clone() };
+        blockchain.submit_transaction(bumped.clone()).unwrap();
+        assert_eq!(blockchain.fee_histogram.counts(), [(bucket(&bumped), 2)]);
+
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert!(blockchain.fee_histogram.counts().is_empty());
+        assert_eq!(blockchain.fee_histogram.rate_to_fit(0), 0.0);
+    }
+
+    #[test]
+    fn timestamps_and_lock_times_follow_median_time_past() {
+        let mut blockchain = funded(&["Alice", "Bob", "Carol"]);
+        let mut timestamps: Vec<i64> = blockchain.chain.iter().map(|block| block.timestamp).collect();
+        timestamps.sort_unstable();
+        assert_eq!(blockchain.median_time_past(), timestamps[2]);
+
+        let bad_timestamp = Err(InvalidBlock { height: 3, reason: BlockRejection::BadTimestamp });
+        let previous_median = median_timestamp(&blockchain.chain[..3]);
+        let mut chain = blockchain.chain.clone();
+        chain[3].timestamp = previous_median;
+        assert_eq!(blockchain.check_chain(&chain), bad_timestamp);
+        chain[3].timestamp = previous_median + 1;
+        assert_eq!(blockchain.check_chain(&chain), Ok(()));
+        chain[3].timestamp = blockchain.clock.now() + MAX_FUTURE_BLOCK_TIME + 3600;
+        assert_eq!(blockchain.check_chain(&chain), bad_timestamp);
+
+        let locked = Transaction { lock_time: blockchain.median_time_past() + 3600, ..payment("Alice", "Bob", 0.1, 0) };
+        blockchain.submit_transaction(locked.clone()).unwrap();
+        let block = blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+        assert!(!block.transactions.contains(&locked));
+        assert_eq!(blockchain.current_transactions, vec![locked.clone()]);
+        let mut chain = blockchain.chain.clone();
+        chain[4].transactions.push(locked);
+        assert_eq!(
+            blockchain.check_chain(&chain),
+            Err(InvalidBlock { height: 4, reason: BlockRejection::NotFinal })
+        );
+    }
+
+    #[test]
+    fn graphs_draw_stale_blocks_beside_the_chain() {
+        let linear = funded(&["Alice"]);
+        let dot = linear.graph(GraphFormat::Dot);
+        assert!(dot.starts_with("digraph chain {") && dot.ends_with('}'));
+        assert!(!dot.contains("dashed"));
+        let tip = linear.last_block().calculate_hash();
+        let genesis = linear.chain[0].calculate_hash();
+        assert!(dot.contains(&format!("    b{} -> b{};", &genesis[..16], &tip[..16])));
+        assert!(dot.contains(&format!("height 1\\n{}\\nwork {}", &tip[..12], 2 * linear.block_work())));
+
+        let forked = generate_chain(&ChainSpec { blocks: 2, transactions_per_block: 0, fork_every: Some(1), ..ChainSpec::default() });
+        assert_eq!(forked.stale_blocks().len(), 2);
+        let dot = forked.graph(GraphFormat::Dot);
+        assert_eq!(dot.matches("style=dashed").count(), 2);
+        assert_eq!(dot.matches(" -> ").count(), forked.chain.len() + 1);
+        let mermaid = forked.graph(GraphFormat::Mermaid);
+        assert!(mermaid.starts_with("graph LR"));
+        assert_eq!(mermaid.matches(" -.-> ").count(), 2);
+        assert_eq!(mermaid.matches(" --> ").count(), forked.chain.len() - 1);
+    }
+
+    #[test]
+    fn traces_report_asset_and_name_writes() {
+        let mut blockchain = funded(&["Alice"]);
+        let mint = Transaction {
+            asset: Some(AssetOperation::Mint { metadata_hash: String::from("ab") }),
+            outputs: Vec::new(),
+            ..payment("Alice", "Alice", 0.0, 0)
+        };
+        let register = Transaction {
+            name: Some(NameOperation::Register { name: String::from("alice"), target: Address::from("Bob") }),
+            ..payment("Alice", "Bob", 0.1, 1)
+        };
+        blockchain.submit_transaction(mint.clone()).unwrap();
+        blockchain.submit_transaction(register.clone()).unwrap();
+        blockchain.new_block("Miner", blockchain.proof_of_work(blockchain.last_block().proof));
+
+        let trace = blockchain.trace_transaction(&mint.hash()).unwrap();
+        let alice = Some(Address::from("Alice"));
+        assert_eq!(trace.asset, Some(AssetChange { asset_id: mint.hash(), before: None, after: alice }));
+        assert_eq!(trace.name, None);
+
+        let trace = blockchain.trace_transaction(&register.hash()).unwrap();
+        let record = NameRecord {
+            owner: Address::from("Alice"),
+            target: Address::from("Bob"),
+            expires_at: 2 + NAME_REGISTRATION_PERIOD,
+        };
+        assert_eq!(trace.name, Some(NameChange { name: String::from("alice"), before: None, after: Some(record) }));
+        assert_eq!(trace.asset, None);
+        let addresses: Vec<&str> = trace.accounts.iter().map(|change| change.address.as_ref()).collect();
+        assert_eq!(addresses, ["Alice", "Bob"]);
+        assert_eq!(trace.accounts[1].after.balance, 0.1);
+
+        assert_eq!(blockchain.trace_transaction("unknown"), None);
+    }
+
+    #[test]
+    fn validate_transaction_checks_raw_encodings() {
+        let blockchain = funded(&["Alice"]);
+        let transaction = payment("Alice", "Bob", 0.5, 0);
+        assert_eq!(blockchain.validate_transaction(&transaction.serialize()), Ok(transaction.hash()));
+        let empty = Transaction { outputs: Vec::new(), ..transaction.clone() };
+        assert_eq!(blockchain.validate_transaction(&empty.serialize()), Err(MempoolError::NoOutputs));
+        let truncated = transaction.serialize();
+        assert_eq!(blockchain.validate_transaction(&truncated[..truncated.len() - 1]), Err(MempoolError::Malformed));
+        assert!(blockchain.current_transactions.is_empty());
+    }
 }